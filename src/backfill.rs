@@ -0,0 +1,699 @@
+use std::pin::Pin;
+
+use anyhow::{format_err, Error};
+use async_stream::stream;
+use futures03::{stream, Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{info, info_span, Instrument};
+
+use crate::cursor::{CursorKey, CursorStore};
+use crate::sink::Sink;
+use crate::substreams_stream::{block_span, BlockResponse, SubstreamsStreamBuilder};
+
+type SegmentResponse = (usize, Result<BlockResponse, Error>);
+
+const MARKER_RUNNING: &str = "running";
+const MARKER_DONE: &str = "done";
+
+/// A `[start_block, stop_block)` part of the backfilled range, streamed on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub start_block: u64,
+    pub stop_block: u64,
+}
+
+/// Splits `[start_block, stop_block)` into at most `count` contiguous segments of
+/// (nearly) equal size, in block order.
+pub fn split_range(start_block: u64, stop_block: u64, count: usize) -> Vec<Segment> {
+    let length = stop_block.saturating_sub(start_block);
+    if length == 0 {
+        return vec![];
+    }
+
+    let size = length.div_ceil(count.max(1) as u64);
+    (start_block..stop_block)
+        .step_by(size as usize)
+        .map(|start| Segment {
+            start_block: start,
+            stop_block: (start + size).min(stop_block),
+        })
+        .collect()
+}
+
+/// Backfills `[start_block, stop_block)` of an output module with one stream per
+/// segment, running concurrently over the endpoint of the builder template.
+///
+/// Output is delivered to the sink in block order: segments are drained one after
+/// the other while the following ones are buffered, up to `buffer_size` blocks per
+/// segment, a segment whose buffer is full waits for the previous ones. Sinks
+/// returning `true` from [Sink::accepts_out_of_order] receive blocks as soon as they
+/// arrive instead, except for the last block of the range which is always delivered
+/// last.
+///
+/// Each segment checkpoints the cursor of the last block it delivered in `checkpoints`,
+/// after flushing the sink, every `checkpoint_interval` blocks and when the backfill
+/// stops, successfully or not. A restarted backfill resumes every segment from its
+/// checkpoint, blocks delivered since the last checkpoint are delivered again. The
+/// range and the segment count must stay the same across restarts for the checkpoints
+/// to be found.
+///
+/// A marker stored along the checkpoints records that the backfill is running, and
+/// then the last segment's cursor once every segment is done. The checkpoints and the
+/// marker are deleted afterwards and [Backfill::run] returns the template configured
+/// to continue live from that cursor.
+pub struct Backfill<C: CursorStore> {
+    template: SubstreamsStreamBuilder,
+    checkpoints: C,
+    key: CursorKey,
+    start_block: u64,
+    stop_block: u64,
+    segments: usize,
+    buffer_size: usize,
+    checkpoint_interval: u64,
+}
+
+impl<C: CursorStore> Backfill<C> {
+    /// `template` configures every stream (endpoint, modules, output module, ...), its
+    /// cursor and block range are only used by the live stream. Segment checkpoints are
+    /// stored under `key` suffixed with the segment's range.
+    pub fn new(template: SubstreamsStreamBuilder, checkpoints: C, key: CursorKey) -> Self {
+        Backfill {
+            template,
+            checkpoints,
+            key,
+            start_block: 0,
+            stop_block: 0,
+            segments: 4,
+            buffer_size: 1000,
+            checkpoint_interval: 1000,
+        }
+    }
+
+    pub fn range(mut self, start_block: u64, stop_block: u64) -> Self {
+        self.start_block = start_block;
+        self.stop_block = stop_block;
+        self
+    }
+
+    pub fn segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    /// Number of blocks buffered per segment while waiting for the previous segments.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    pub fn checkpoint_interval(mut self, blocks: u64) -> Self {
+        self.checkpoint_interval = blocks;
+        self
+    }
+
+    /// Backfills into `sink` and returns the builder of the live stream. A marker is
+    /// stored next to the checkpoints for as long as the backfill is not complete, when
+    /// it is missing and the sink has a cursor the backfill already handed off and the
+    /// live stream resumes from the sink's cursor.
+    pub async fn run<K: Sink + ?Sized>(
+        self,
+        sink: &mut K,
+    ) -> Result<SubstreamsStreamBuilder, Error> {
+        let template = self.template.clone();
+        self.run_with(sink, |segment, cursor| {
+            template
+                .clone()
+                .cursor(cursor)
+                .start_block(segment.start_block as i64)
+                .stop_block(segment.stop_block)
+                .final_blocks_only(true)
+                .build()
+        })
+        .await
+    }
+
+    async fn run_with<K, O, S>(
+        self,
+        sink: &mut K,
+        open: O,
+    ) -> Result<SubstreamsStreamBuilder, Error>
+    where
+        K: Sink + ?Sized,
+        O: FnMut(&Segment, Option<String>) -> Result<S, Error>,
+        S: Stream<Item = Result<BlockResponse, Error>> + Send + 'static,
+    {
+        let segments = split_range(self.start_block, self.stop_block, self.segments);
+        let keys: Vec<CursorKey> = segments
+            .iter()
+            .map(|segment| self.segment_key(segment))
+            .collect();
+        let marker_key = self.marker_key();
+
+        match self.checkpoints.load(&marker_key)?.as_deref() {
+            None => {
+                let sink_cursor = sink.cursor()?;
+                if sink_cursor.is_some() {
+                    info!("Backfill already completed, resuming live stream from sink cursor");
+                    return Ok(self.template.cursor(sink_cursor));
+                }
+
+                // Stored before any block is delivered, the sink's cursor alone cannot
+                // tell an interrupted backfill from a completed one
+                self.checkpoints.save(&marker_key, MARKER_RUNNING)?;
+            }
+            Some(MARKER_RUNNING) => info!("Resuming interrupted backfill from its checkpoints"),
+            Some(marker) => {
+                let cursor = marker
+                    .strip_prefix(MARKER_DONE)
+                    .ok_or_else(|| format_err!("invalid backfill marker '{}'", marker))?
+                    .trim();
+
+                return self.complete(&keys, Some(cursor).filter(|c| !c.is_empty()));
+            }
+        }
+
+        let mut checkpoints = Vec::with_capacity(keys.len());
+        for key in &keys {
+            checkpoints.push(self.checkpoints.load(key)?);
+        }
+
+        info!(
+            start_block = self.start_block,
+            stop_block = self.stop_block,
+            segments = segments.len(),
+            "Backfilling"
+        );
+
+        let checkpoint = |index: usize, cursor: &str| self.checkpoints.save(&keys[index], cursor);
+        let cursors = backfill(
+            &segments,
+            checkpoints,
+            open,
+            sink,
+            checkpoint,
+            self.buffer_size,
+            self.checkpoint_interval,
+        )
+        .await?;
+
+        // Checkpoints are only deleted once the live cursor is recorded, deleting them
+        // could otherwise be interrupted with segments left to start over
+        let cursor = cursors.into_iter().last().flatten();
+        self.checkpoints.save(
+            &marker_key,
+            &format!("{} {}", MARKER_DONE, cursor.as_deref().unwrap_or_default()),
+        )?;
+
+        self.complete(&keys, cursor.as_deref())
+    }
+
+    /// Deletes the checkpoints, then the marker, and returns the live stream's builder.
+    fn complete(
+        &self,
+        keys: &[CursorKey],
+        cursor: Option<&str>,
+    ) -> Result<SubstreamsStreamBuilder, Error> {
+        for key in keys {
+            self.checkpoints.delete(key)?;
+        }
+        self.checkpoints.delete(&self.marker_key())?;
+
+        let live = match cursor {
+            Some(cursor) => self.template.clone().cursor(Some(cursor.to_string())),
+            None => self
+                .template
+                .clone()
+                .cursor(None)
+                .start_block(self.stop_block as i64),
+        };
+
+        info!("Backfill completed, handing off to live stream");
+        Ok(live)
+    }
+
+    fn marker_key(&self) -> CursorKey {
+        CursorKey::new(
+            &self.key.endpoint,
+            &self.key.package,
+            format!(
+                "{}@backfill-{}-{}",
+                self.key.output_module, self.start_block, self.stop_block
+            ),
+        )
+    }
+
+    fn segment_key(&self, segment: &Segment) -> CursorKey {
+        CursorKey::new(
+            &self.key.endpoint,
+            &self.key.package,
+            format!(
+                "{}@{}-{}",
+                self.key.output_module, segment.start_block, segment.stop_block
+            ),
+        )
+    }
+}
+
+/// Streams every segment concurrently, from its checkpointed cursor, into `sink` and
+/// returns the last cursor of each segment.
+#[allow(clippy::too_many_arguments)]
+async fn backfill<K, O, S, P>(
+    segments: &[Segment],
+    mut cursors: Vec<Option<String>>,
+    mut open: O,
+    sink: &mut K,
+    mut checkpoint: P,
+    buffer_size: usize,
+    checkpoint_interval: u64,
+) -> Result<Vec<Option<String>>, Error>
+where
+    K: Sink + ?Sized,
+    O: FnMut(&Segment, Option<String>) -> Result<S, Error>,
+    S: Stream<Item = Result<BlockResponse, Error>> + Send + 'static,
+    P: FnMut(usize, &str) -> Result<(), Error>,
+{
+    let mut receivers = Vec::with_capacity(segments.len());
+    for (segment, cursor) in segments.iter().zip(&cursors) {
+        let mut stream = Box::pin(open(segment, cursor.clone())?);
+        let (sender, receiver) = mpsc::channel(buffer_size.max(1));
+
+        // Dropping the receiver, when the backfill fails, stops the segment's stream
+        let span = info_span!(
+            "segment",
+            start_block = segment.start_block,
+            stop_block = segment.stop_block
+        );
+        tokio::spawn(
+            async move {
+                while let Some(response) = stream.next().await {
+                    if sender.send(response).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            .instrument(span),
+        );
+
+        receivers.push(ReceiverStream::new(receiver));
+    }
+
+    let responses: Pin<Box<dyn Stream<Item = SegmentResponse>>> = match sink.accepts_out_of_order()
+    {
+        true => {
+            let last = receivers.len().saturating_sub(1);
+            let mut merged = stream::select_all(
+                receivers
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, receiver)| receiver.map(move |response| (index, response))),
+            );
+
+            // The last block of the range is held back until every segment is done, so
+            // that the sink ends on its cursor as when delivering in block order
+            Box::pin(stream! {
+                let mut held = None;
+                while let Some((index, response)) = merged.next().await {
+                    match response {
+                        Ok(BlockResponse::New(data)) if index == last => {
+                            if let Some(previous) = held.replace(data) {
+                                yield (index, Ok(BlockResponse::New(previous)));
+                            }
+                        }
+                        response => yield (index, response),
+                    }
+                }
+
+                if let Some(data) = held {
+                    yield (last, Ok(BlockResponse::New(data)));
+                }
+            })
+        }
+        false => Box::pin(
+            stream::iter(receivers.into_iter().enumerate())
+                .flat_map(|(index, receiver)| receiver.map(move |response| (index, response))),
+        ),
+    };
+
+    let mut state = Checkpoints {
+        dirty: vec![false; segments.len()],
+        pending: 0,
+        checkpoint_interval,
+    };
+
+    let result = deliver(responses, sink, &mut cursors, &mut state, &mut checkpoint).await;
+
+    // Persist what was delivered so far, whether the backfill failed or not
+    let saved = state.save(sink, &cursors, &mut checkpoint);
+    result?;
+    saved?;
+
+    Ok(cursors)
+}
+
+struct Checkpoints {
+    dirty: Vec<bool>,
+    pending: u64,
+    checkpoint_interval: u64,
+}
+
+impl Checkpoints {
+    fn save<K, P>(
+        &mut self,
+        sink: &mut K,
+        cursors: &[Option<String>],
+        checkpoint: &mut P,
+    ) -> Result<(), Error>
+    where
+        K: Sink + ?Sized,
+        P: FnMut(usize, &str) -> Result<(), Error>,
+    {
+        sink.flush()?;
+
+        for (index, dirty) in self.dirty.iter_mut().enumerate() {
+            if let (true, Some(cursor)) = (*dirty, &cursors[index]) {
+                checkpoint(index, cursor)?;
+            }
+            *dirty = false;
+        }
+
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+async fn deliver<R, K, P>(
+    mut responses: R,
+    sink: &mut K,
+    cursors: &mut [Option<String>],
+    state: &mut Checkpoints,
+    checkpoint: &mut P,
+) -> Result<(), Error>
+where
+    R: Stream<Item = SegmentResponse> + Unpin,
+    K: Sink + ?Sized,
+    P: FnMut(usize, &str) -> Result<(), Error>,
+{
+    while let Some((index, response)) = responses.next().await {
+        let cursor = match response
+            .map_err(|e| e.context(format!("segment #{} of the backfill failed", index)))?
+        {
+            BlockResponse::New(data) => {
+                block_span(&data).in_scope(|| sink.handle_block(&data))?;
+                data.cursor
+            }
+            BlockResponse::Undo(signal) => {
+                sink.handle_undo(&signal)?;
+                signal.last_valid_cursor
+            }
+            BlockResponse::Session(session) => {
+                sink.handle_session(&session)?;
+                continue;
+            }
+            BlockResponse::Progress(progress) => {
+                sink.handle_progress(&progress)?;
+                continue;
+            }
+        };
+
+        cursors[index] = Some(cursor);
+        state.dirty[index] = true;
+        state.pending += 1;
+
+        if state.pending >= state.checkpoint_interval {
+            state.save(sink, cursors, checkpoint)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, time::Duration};
+
+    use anyhow::format_err;
+
+    use super::*;
+    use crate::cursor::FileCursorStore;
+    use crate::pb::sf::substreams::{rpc::v2::BlockScopedData, v1::BlockRef};
+    use crate::sink::tests::block;
+    use crate::substreams_stream::SubstreamsStream;
+
+    #[test]
+    fn splits_range_into_segments() {
+        let segment = |start_block, stop_block| Segment {
+            start_block,
+            stop_block,
+        };
+
+        assert_eq!(
+            split_range(100, 110, 3),
+            vec![segment(100, 104), segment(104, 108), segment(108, 110)]
+        );
+        assert_eq!(
+            split_range(100, 102, 4),
+            vec![segment(100, 101), segment(101, 102)]
+        );
+        assert_eq!(split_range(100, 100, 4), vec![]);
+        assert_eq!(split_range(0, 10, 0), vec![segment(0, 10)]);
+    }
+
+    fn segment_blocks(segment: &Segment, cursor: Option<String>) -> Vec<BlockResponse> {
+        let resume_after = cursor.map(|c| c.trim_start_matches("cursor-").parse::<u64>().unwrap());
+
+        (segment.start_block..segment.stop_block)
+            .filter(|number| resume_after.map_or(true, |after| *number > after))
+            .map(|number| block(number, &number.to_string(), number))
+            .collect()
+    }
+
+    /// Later segments are faster, the first one only starts once they are done.
+    fn open(
+        segment: &Segment,
+        cursor: Option<String>,
+    ) -> Result<impl Stream<Item = Result<BlockResponse, Error>>, Error> {
+        let delay = Duration::from_millis(100 - 10 * segment.start_block);
+        let blocks = segment_blocks(segment, cursor);
+
+        Ok(stream::once(async move {
+            tokio::time::sleep(delay).await;
+            stream::iter(blocks.into_iter().map(Ok))
+        })
+        .flatten())
+    }
+
+    /// Records the order blocks are received in, and the cursor of the last one like
+    /// sinks persisting it along every block do.
+    #[derive(Default)]
+    struct RecordingSink {
+        received: Vec<u64>,
+        cursor: Option<String>,
+        out_of_order: bool,
+    }
+
+    impl Sink for RecordingSink {
+        fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+            self.received.push(data.clock.as_ref().unwrap().number);
+            self.cursor = Some(data.cursor.clone());
+            Ok(())
+        }
+
+        fn undo(&mut self, _: &BlockRef) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn cursor(&self) -> Result<Option<String>, Error> {
+            Ok(self.cursor.clone())
+        }
+
+        fn accepts_out_of_order(&self) -> bool {
+            self.out_of_order
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivers_segments_in_block_order() {
+        let segments = split_range(0, 9, 3);
+        let mut sink = RecordingSink::default();
+        let mut checkpoints = HashMap::new();
+
+        let cursors = backfill(
+            &segments,
+            vec![None; 3],
+            open,
+            &mut sink,
+            |index, cursor: &str| {
+                checkpoints.insert(index, cursor.to_string());
+                Ok(())
+            },
+            2,
+            4,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sink.received, (0..9).collect::<Vec<_>>());
+        assert_eq!(cursors.last().unwrap().as_deref(), Some("cursor-8"));
+        assert_eq!(checkpoints[&0], "cursor-2");
+        assert_eq!(checkpoints[&2], "cursor-8");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivers_out_of_order_and_resumes_from_checkpoints() {
+        let segments = split_range(0, 9, 3);
+        let mut sink = RecordingSink {
+            out_of_order: true,
+            ..Default::default()
+        };
+
+        backfill(
+            &segments,
+            vec![
+                Some("cursor-1".to_string()),
+                None,
+                Some("cursor-8".to_string()),
+            ],
+            open,
+            &mut sink,
+            |_, _: &str| Ok(()),
+            10,
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sink.received, vec![3, 4, 5, 2]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivers_last_block_of_range_last_out_of_order() {
+        let segments = split_range(0, 9, 3);
+        let mut sink = RecordingSink {
+            out_of_order: true,
+            ..Default::default()
+        };
+
+        backfill(
+            &segments,
+            vec![None; 3],
+            open,
+            &mut sink,
+            |_, _: &str| Ok(()),
+            10,
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(sink.received, vec![6, 7, 3, 4, 5, 0, 1, 2, 8]);
+        assert_eq!(sink.cursor.as_deref(), Some("cursor-8"));
+    }
+
+    #[tokio::test]
+    async fn checkpoints_are_saved_when_a_segment_fails() {
+        let segments = split_range(0, 4, 2);
+        let mut sink = RecordingSink::default();
+        let mut checkpoints = HashMap::new();
+
+        let err = backfill(
+            &segments,
+            vec![None; 2],
+            |segment: &Segment, _| {
+                let mut responses: Vec<_> =
+                    segment_blocks(segment, None).into_iter().map(Ok).collect();
+                if segment.start_block == 2 {
+                    responses.insert(1, Err(format_err!("boom")));
+                }
+                Ok(stream::iter(responses))
+            },
+            &mut sink,
+            |index, cursor: &str| {
+                checkpoints.insert(index, cursor.to_string());
+                Ok(())
+            },
+            10,
+            10,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            format!("{:#}", err),
+            "segment #1 of the backfill failed: boom"
+        );
+        assert_eq!(sink.received, vec![0, 1, 2]);
+        assert_eq!(checkpoints[&0], "cursor-1");
+        assert_eq!(checkpoints[&1], "cursor-2");
+    }
+
+    fn backfill_of(store: &FileCursorStore) -> Backfill<FileCursorStore> {
+        Backfill::new(
+            SubstreamsStream::builder(),
+            store.clone(),
+            CursorKey::new("localhost:9000", "pkg.spkg", "map_test"),
+        )
+        .range(0, 9)
+        .segments(3)
+        .checkpoint_interval(100)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_after_crash_before_first_checkpoint() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path()).with_extension("checkpoint");
+        let mut sink = RecordingSink::default();
+
+        // Segments stall after their first block and the process crashes, before any
+        // checkpoint but after the sink recorded a cursor
+        let stalled = |segment: &Segment, cursor: Option<String>| {
+            let first = segment_blocks(segment, cursor).into_iter().take(1).map(Ok);
+            Ok(stream::iter(first).chain(stream::pending()))
+        };
+        let crashed = tokio::time::timeout(
+            Duration::from_secs(1),
+            backfill_of(&store).run_with(&mut sink, stalled),
+        )
+        .await;
+        assert!(crashed.is_err());
+        assert_eq!(sink.received, vec![0]);
+        assert_eq!(sink.cursor.as_deref(), Some("cursor-0"));
+
+        // The marker left behind resumes the backfill instead of going live mid-range
+        backfill_of(&store).run_with(&mut sink, open).await.unwrap();
+        assert_eq!(
+            sink.received,
+            std::iter::once(0).chain(0..9).collect::<Vec<_>>()
+        );
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 0);
+
+        // Completed, the next run goes live from the sink's cursor
+        backfill_of(&store).run_with(&mut sink, open).await.unwrap();
+        assert_eq!(sink.received.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn completes_cleanup_interrupted_after_done_marker() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path()).with_extension("checkpoint");
+        let backfill = backfill_of(&store);
+        store.save(&backfill.marker_key(), "done cursor-8").unwrap();
+        store
+            .save(&backfill.segment_key(&split_range(0, 9, 3)[2]), "cursor-8")
+            .unwrap();
+
+        let mut sink = RecordingSink::default();
+        backfill
+            .run_with(
+                &mut sink,
+                |_: &Segment, _| -> Result<stream::Empty<_>, Error> {
+                    panic!("no segment is streamed again")
+                },
+            )
+            .await
+            .unwrap();
+
+        assert!(sink.received.is_empty());
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+}
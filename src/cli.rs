@@ -0,0 +1,689 @@
+use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+
+use anyhow::{format_err, Context, Error};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+/// Command line of the reference sink, kept apart from `main` so that parsing can be
+/// tested without the process arguments.
+#[derive(Parser, Debug)]
+#[command(
+    name = "substreams-sink-rust",
+    version,
+    about = "Streams Substreams module outputs into a sink"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Logged levels, either a single level (`debug`) or comma separated
+    /// `<target>=<level>` directives (`warn,substreams_sink_rust=debug`)
+    #[arg(long, global = true, value_name = "FILTER", env = "SUBSTREAMS_LOG_LEVEL", default_value = "info", value_parser = parse_log_filter)]
+    pub log_level: String,
+
+    /// How logs are written to standard error
+    #[arg(long, global = true, value_enum, env = "SUBSTREAMS_LOG_FORMAT", default_value_t = LogFormat::Plain)]
+    pub log_format: LogFormat,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Streams the output module of a package into the configured sink
+    Run(Box<RunArgs>),
+
+    /// Prints the package name, version, network and modules
+    Info(PackageArgs),
+
+    /// Inspects the modules of a package
+    #[command(subcommand)]
+    Package(PackageCommand),
+
+    /// Manages the cursor persisted for an endpoint, package and output module
+    #[command(subcommand)]
+    Cursor(CursorCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PackageCommand {
+    /// Prints the kind, inputs, output type, initial block, block filter and doc of
+    /// each module
+    Inspect(InspectArgs),
+
+    /// Prints the module dependency graph in Graphviz DOT or Mermaid format
+    Graph(GraphArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CursorCommand {
+    /// Prints the persisted cursor
+    Show(CursorArgs),
+
+    /// Deletes the persisted cursor, the next run starts from the start block again
+    Reset(CursorArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    #[command(flatten)]
+    pub endpoint: EndpointArgs,
+
+    #[command(flatten)]
+    pub package: PackageSourceArgs,
+
+    /// Name of the module whose output is streamed
+    pub module: String,
+
+    /// Block range `<start>:<stop>`, both are optional and can be relative (`+<count>`):
+    /// `<start>` to the module's initial block, `<stop>` to the start block. A negative
+    /// start is relative to the chain head and `-` or no stop streams forever.
+    #[arg(short, long, allow_hyphen_values = true)]
+    pub range: Option<BlockRange>,
+
+    /// Network whose initial blocks and params are applied to the modules, defaults
+    /// to the package's network
+    #[arg(short, long, env = "SUBSTREAMS_NETWORK")]
+    pub network: Option<String>,
+
+    /// Overrides the params of a module, can be repeated
+    #[arg(short, long = "params", value_name = "MODULE=VALUE", value_parser = parse_param)]
+    pub params: Vec<(String, String)>,
+
+    /// Starts from the first block served by the endpoint when the start block is below
+    /// it, instead of failing
+    #[arg(long)]
+    pub clamp_start_block: bool,
+
+    /// Skips checking the endpoint's chain and first block against the package
+    #[arg(long)]
+    pub skip_endpoint_check: bool,
+
+    /// Only receives final blocks, no undo signals are ever received
+    #[arg(long)]
+    pub final_only: bool,
+
+    /// Backfills from the start block up to this block (exclusive) with concurrent
+    /// streams before continuing live. Not available with `--output-file`, which
+    /// would get the blocks delivered again after a restart appended twice
+    #[arg(
+        long,
+        value_name = "BLOCK",
+        requires = "backfill_segments",
+        conflicts_with = "output_file"
+    )]
+    pub backfill_stop_block: Option<u64>,
+
+    /// Number of concurrent streams the backfill range is split into
+    #[arg(long, value_name = "COUNT", requires = "backfill_stop_block", value_parser = clap::value_parser!(u32).range(1..))]
+    pub backfill_segments: Option<u32>,
+
+    /// Number of blocks buffered per backfill segment while waiting for the previous
+    /// segments to be delivered
+    #[arg(long, value_name = "BLOCKS", requires = "backfill_stop_block", default_value_t = 1000, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub backfill_buffer_size: usize,
+
+    /// Delivers (and prints) backfilled blocks as soon as any segment receives them,
+    /// instead of in block order
+    #[arg(long, requires = "backfill_stop_block")]
+    pub backfill_out_of_order: bool,
+
+    /// Minimum number of seconds between two progress reports, defaults to 1 when the
+    /// progress is redrawn in place on a terminal and to 30 when it is logged
+    #[arg(long, value_name = "SECONDS", env = "SUBSTREAMS_PROGRESS_INTERVAL")]
+    pub progress_interval: Option<u64>,
+
+    /// Serves Prometheus metrics at `http://<ADDR>/metrics`
+    #[arg(long, value_name = "ADDR", env = "SUBSTREAMS_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// How module outputs are printed to standard output
+    #[arg(short, long, value_enum, env = "SUBSTREAMS_OUTPUT_FORMAT", default_value_t = OutputFormat::Text)]
+    pub output: OutputFormat,
+
+    /// Only processes blocks once final (`final`) or once the given number of blocks
+    /// were received on top of them
+    #[arg(long, value_name = "final|DEPTH", env = "SUBSTREAMS_REORG_BUFFER")]
+    pub reorg_buffer: Option<ReorgBufferMode>,
+
+    #[command(flatten)]
+    pub sink: SinkArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct PackageArgs {
+    #[command(flatten)]
+    pub package: PackageSourceArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    pub package: PackageSourceArgs,
+
+    /// Prints the package and its modules as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct GraphArgs {
+    #[command(flatten)]
+    pub package: PackageSourceArgs,
+
+    /// Only prints the modules needed to compute this output module
+    #[arg(short, long)]
+    pub module: Option<String>,
+
+    /// Graph format
+    #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+    pub format: GraphFormat,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// Mermaid flowchart
+    Mermaid,
+}
+
+/// Where the package is read from, remote packages are cached on disk.
+#[derive(Args, Debug)]
+pub struct PackageSourceArgs {
+    /// Package, either a local `.spkg` file, an URL or `<package>@<version>` to fetch
+    /// from the spkg.io registry
+    #[arg(value_name = "PACKAGE")]
+    pub package: String,
+
+    /// Fails unless the SHA-256 of the raw package file is this hex digest
+    #[arg(long, value_name = "HEX")]
+    pub expect_sha256: Option<String>,
+
+    /// Only reads remote packages from the cache, fails when not cached
+    #[arg(long, env = "SUBSTREAMS_OFFLINE")]
+    pub offline: bool,
+
+    /// Directory where remote packages are cached [default: ~/.cache/substreams/spkg]
+    #[arg(long, env = "SUBSTREAMS_SPKG_CACHE_DIR")]
+    pub spkg_cache_dir: Option<PathBuf>,
+
+    /// Seconds after which packages that may change (`latest` versions and URLs) are
+    /// fetched again, pinned versions are always served from the cache
+    #[arg(long, env = "SUBSTREAMS_SPKG_CACHE_TTL", default_value_t = 3600)]
+    pub spkg_cache_ttl: u64,
+}
+
+#[derive(Args, Debug)]
+pub struct CursorArgs {
+    /// Substreams endpoint the cursor was received from
+    #[arg(short, long, env = "SUBSTREAMS_ENDPOINT")]
+    pub endpoint: String,
+
+    /// Uses plaintext HTTP when the endpoint has no scheme
+    #[arg(long)]
+    pub plaintext: bool,
+
+    /// Package the cursor was received for, as given to `run`
+    pub package: String,
+
+    /// Output module the cursor was received for
+    pub module: String,
+
+    #[command(flatten)]
+    pub sink: SinkArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct EndpointArgs {
+    /// Substreams endpoint, `https://` is assumed when no scheme is given
+    #[arg(short, long, env = "SUBSTREAMS_ENDPOINT")]
+    pub endpoint: String,
+
+    /// Substreams API token
+    #[arg(long, env = "SUBSTREAMS_API_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Uses plaintext HTTP when the endpoint has no scheme
+    #[arg(long)]
+    pub plaintext: bool,
+}
+
+impl EndpointArgs {
+    pub fn url(&self) -> String {
+        endpoint_url(&self.endpoint, self.plaintext)
+    }
+}
+
+impl CursorArgs {
+    pub fn endpoint_url(&self) -> String {
+        endpoint_url(&self.endpoint, self.plaintext)
+    }
+}
+
+fn endpoint_url(endpoint: &str, plaintext: bool) -> String {
+    if endpoint.starts_with("http") {
+        return endpoint.to_string();
+    }
+
+    match plaintext {
+        true => format!("http://{}", endpoint),
+        false => format!("https://{}", endpoint),
+    }
+}
+
+/// Where module outputs and cursor are persisted, when neither `--sqlite-path` nor
+/// `--output-file` is set only the cursor is persisted in `--cursor-dir`.
+#[derive(Args, Debug)]
+pub struct SinkArgs {
+    /// Directory where cursors are persisted when no output sink is set
+    #[arg(long, env = "SUBSTREAMS_CURSOR_DIR", default_value = "cursors")]
+    pub cursor_dir: PathBuf,
+
+    /// Writes module outputs and cursor together to this SQLite database
+    #[arg(long, env = "SUBSTREAMS_SQLITE_PATH", conflicts_with = "output_file")]
+    pub sqlite_path: Option<PathBuf>,
+
+    /// Number of blocks committed at once to the SQLite database
+    #[arg(long, env = "SUBSTREAMS_SQLITE_BATCH_SIZE", default_value_t = 1)]
+    pub sqlite_batch_size: usize,
+
+    /// Appends module outputs and cursor to this file, one line per block
+    #[arg(long, env = "SUBSTREAMS_OUTPUT_FILE")]
+    pub output_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human readable summary and decoded output
+    Text,
+    /// One JSON object per block
+    Jsonl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// One human readable line per event
+    Plain,
+    /// One JSON object per event, with the fields of its spans
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReorgBufferMode {
+    Final,
+    Depth(u64),
+}
+
+impl FromStr for ReorgBufferMode {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "final" => Ok(ReorgBufferMode::Final),
+            depth => depth
+                .parse::<u64>()
+                .map(ReorgBufferMode::Depth)
+                .context("must be `final` or a valid integer"),
+        }
+    }
+}
+
+/// One bound of a [BlockRange] as written on the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Bound {
+    #[default]
+    Unset,
+    Absolute(i64),
+    Relative(u64),
+}
+
+/// Block range given as `<start>:<stop>`, resolved against the output module's
+/// initial block by [BlockRange::resolve].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockRange {
+    pub start: Bound,
+    pub stop: Bound,
+}
+
+impl FromStr for BlockRange {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (prefix, suffix) = input.split_once(':').unwrap_or(("", input));
+
+        let start = match prefix {
+            "" => Bound::Unset,
+            x if x.starts_with('+') => Bound::Relative(
+                x.trim_start_matches('+')
+                    .parse::<u64>()
+                    .context("<start> is not a valid integer")?,
+            ),
+            x => Bound::Absolute(x.parse::<i64>().context("<start> is not a valid integer")?),
+        };
+
+        let stop = match suffix {
+            "" | "-" => Bound::Unset,
+            x if x.starts_with('+') => Bound::Relative(
+                x.trim_start_matches('+')
+                    .parse::<u64>()
+                    .context("<stop> is not a valid integer")?,
+            ),
+            x => Bound::Absolute(x.parse::<u64>().context("<stop> is not a valid integer")? as i64),
+        };
+
+        Ok(BlockRange { start, stop })
+    }
+}
+
+impl BlockRange {
+    /// Returns the `(start, stop)` blocks of the request, a stop of 0 streams forever.
+    pub fn resolve(&self, initial_block: u64) -> (i64, u64) {
+        let start = match self.start {
+            Bound::Unset => initial_block as i64,
+            Bound::Absolute(start) => start,
+            Bound::Relative(count) => (initial_block + count) as i64,
+        };
+
+        let stop = match self.stop {
+            Bound::Unset => 0,
+            Bound::Absolute(stop) => stop as u64,
+            Bound::Relative(count) => start as u64 + count,
+        };
+
+        (start, stop)
+    }
+}
+
+fn parse_param(input: &str) -> Result<(String, String), Error> {
+    let (module, value) = input
+        .split_once('=')
+        .ok_or_else(|| format_err!("must be in the `<module>=<value>` form"))?;
+
+    if module.is_empty() {
+        return Err(format_err!("module name is empty"));
+    }
+
+    Ok((module.to_string(), value.to_string()))
+}
+
+fn parse_log_filter(input: &str) -> Result<String, Error> {
+    tracing_subscriber::EnvFilter::try_new(input)?;
+    Ok(input.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::error::ErrorKind;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("substreams-sink-rust").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_run_flags() {
+        let cli = parse(&[
+            "run",
+            "-e",
+            "localhost:9000",
+            "--plaintext",
+            "--token",
+            "secret",
+            "ethereum-explorer@v0.1.2",
+            "map_block_meta",
+            "--network",
+            "sepolia",
+            "--range",
+            "100:+10",
+            "-p",
+            "map_block_meta=address=0x01",
+            "-p",
+            "store_pools=",
+            "--final-only",
+            "--output",
+            "jsonl",
+            "--reorg-buffer",
+            "12",
+            "--sqlite-path",
+            "out.db",
+            "--sqlite-batch-size",
+            "50",
+            "--backfill-stop-block",
+            "18000000",
+            "--backfill-segments",
+            "8",
+            "--backfill-buffer-size",
+            "50",
+            "--backfill-out-of-order",
+            "--progress-interval",
+            "5",
+            "--metrics-addr",
+            "127.0.0.1:9102",
+        ])
+        .unwrap();
+
+        let Command::Run(args) = cli.command else {
+            panic!("expected run command");
+        };
+        assert_eq!(args.endpoint.url(), "http://localhost:9000");
+        assert_eq!(args.endpoint.token.as_deref(), Some("secret"));
+        assert_eq!(args.package.package, "ethereum-explorer@v0.1.2");
+        assert!(!args.package.offline);
+        assert_eq!(args.module, "map_block_meta");
+        assert_eq!(args.network.as_deref(), Some("sepolia"));
+        assert_eq!(args.range.unwrap_or_default().resolve(0), (100, 110));
+        assert_eq!(
+            args.params,
+            vec![
+                ("map_block_meta".to_string(), "address=0x01".to_string()),
+                ("store_pools".to_string(), "".to_string()),
+            ]
+        );
+        assert!(args.final_only);
+        assert_eq!(args.output, OutputFormat::Jsonl);
+        assert_eq!(args.reorg_buffer, Some(ReorgBufferMode::Depth(12)));
+        assert_eq!(args.sink.sqlite_path, Some(PathBuf::from("out.db")));
+        assert_eq!(args.sink.sqlite_batch_size, 50);
+        assert_eq!(args.backfill_stop_block, Some(18_000_000));
+        assert_eq!(args.backfill_segments, Some(8));
+        assert_eq!(args.backfill_buffer_size, 50);
+        assert!(args.backfill_out_of_order);
+        assert_eq!(args.progress_interval, Some(5));
+        assert_eq!(args.metrics_addr, Some("127.0.0.1:9102".parse().unwrap()));
+
+        let Command::Run(args) = parse(&[
+            "run",
+            "-e",
+            "localhost:9000",
+            "pkg.spkg",
+            "map",
+            "-r",
+            "-100:",
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("expected run command");
+        };
+        assert_eq!(args.endpoint.url(), "https://localhost:9000");
+        assert_eq!(args.range.unwrap_or_default().resolve(0), (-100, 0));
+    }
+
+    #[test]
+    fn rejects_invalid_run_flags() {
+        let kind = |args: &[&str]| parse(args).unwrap_err().kind();
+
+        assert_eq!(
+            kind(&["run", "-e", "localhost:9000", "pkg.spkg"]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind(&[
+                "run",
+                "-e",
+                "localhost:9000",
+                "pkg.spkg",
+                "map",
+                "-r",
+                "a:b"
+            ]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind(&[
+                "run",
+                "-e",
+                "localhost:9000",
+                "pkg.spkg",
+                "map",
+                "-p",
+                "no_value"
+            ]),
+            ErrorKind::ValueValidation
+        );
+        assert_eq!(
+            kind(&[
+                "run",
+                "-e",
+                "localhost:9000",
+                "pkg.spkg",
+                "map",
+                "--output-file",
+                "a",
+                "--sqlite-path",
+                "b"
+            ]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind(&[
+                "run",
+                "-e",
+                "localhost:9000",
+                "pkg.spkg",
+                "map",
+                "--backfill-segments",
+                "4"
+            ]),
+            ErrorKind::MissingRequiredArgument
+        );
+        assert_eq!(
+            kind(&[
+                "run",
+                "-e",
+                "localhost:9000",
+                "pkg.spkg",
+                "map",
+                "--backfill-stop-block",
+                "100",
+                "--backfill-segments",
+                "4",
+                "--output-file",
+                "a"
+            ]),
+            ErrorKind::ArgumentConflict
+        );
+        assert_eq!(
+            kind(&["package"]),
+            ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+        );
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let Command::Info(args) = parse(&["info", "pkg.spkg", "--offline"]).unwrap().command else {
+            panic!("expected info command");
+        };
+        assert_eq!(args.package.package, "pkg.spkg");
+        assert!(args.package.offline);
+
+        let Command::Package(PackageCommand::Graph(args)) = parse(&[
+            "package",
+            "graph",
+            "pkg.spkg",
+            "-m",
+            "map_swaps",
+            "--format",
+            "mermaid",
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("expected package graph command");
+        };
+        assert_eq!(args.module.as_deref(), Some("map_swaps"));
+        assert_eq!(args.format, GraphFormat::Mermaid);
+        assert!(matches!(
+            parse(&["package", "inspect", "pkg.spkg", "--json"])
+                .unwrap()
+                .command,
+            Command::Package(PackageCommand::Inspect(InspectArgs { json: true, .. }))
+        ));
+
+        let Command::Cursor(CursorCommand::Reset(args)) = parse(&[
+            "cursor",
+            "reset",
+            "-e",
+            "mainnet.eth.streamingfast.io:443",
+            "pkg.spkg",
+            "map",
+        ])
+        .unwrap()
+        .command
+        else {
+            panic!("expected cursor reset command");
+        };
+        assert_eq!(
+            args.endpoint_url(),
+            "https://mainnet.eth.streamingfast.io:443"
+        );
+        assert_eq!(args.module, "map");
+    }
+
+    #[test]
+    fn parses_log_flags() {
+        let cli = parse(&["info", "pkg.spkg"]).unwrap();
+        assert_eq!(cli.log_level, "info");
+        assert_eq!(cli.log_format, LogFormat::Plain);
+
+        let cli = parse(&[
+            "info",
+            "pkg.spkg",
+            "--log-level",
+            "warn,substreams_sink_rust=debug",
+            "--log-format",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(cli.log_level, "warn,substreams_sink_rust=debug");
+        assert_eq!(cli.log_format, LogFormat::Json);
+
+        assert_eq!(
+            parse(&[
+                "--log-level",
+                "substreams_sink_rust=loud",
+                "info",
+                "pkg.spkg"
+            ])
+            .unwrap_err()
+            .kind(),
+            ErrorKind::ValueValidation
+        );
+    }
+
+    #[test]
+    fn block_range_resolution() {
+        let resolve = |input: &str| input.parse::<BlockRange>().unwrap().resolve(1000);
+
+        assert_eq!(resolve(""), (1000, 0));
+        assert_eq!(resolve("2000"), (1000, 2000));
+        assert_eq!(resolve("1500:"), (1500, 0));
+        assert_eq!(resolve("1500:-"), (1500, 0));
+        assert_eq!(resolve("1500:1600"), (1500, 1600));
+        assert_eq!(resolve("+10:+5"), (1010, 1015));
+        assert_eq!(resolve(":+5"), (1000, 1005));
+        assert_eq!(resolve("-100:"), (-100, 0));
+
+        assert!("1500:abc".parse::<BlockRange>().is_err());
+        assert!("1500:-10".parse::<BlockRange>().is_err());
+    }
+}
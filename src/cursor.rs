@@ -0,0 +1,365 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{format_err, Context, Error};
+use tempfile::NamedTempFile;
+
+/// Identifies a cursor, a cursor is only valid for the exact same endpoint, package
+/// and output module it was received for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CursorKey {
+    pub endpoint: String,
+    pub package: String,
+    pub output_module: String,
+}
+
+impl CursorKey {
+    pub fn new<E: Into<String>, P: Into<String>, M: Into<String>>(
+        endpoint: E,
+        package: P,
+        output_module: M,
+    ) -> Self {
+        CursorKey {
+            endpoint: endpoint.into(),
+            package: package.into(),
+            output_module: output_module.into(),
+        }
+    }
+}
+
+impl Display for CursorKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            self.endpoint, self.package, self.output_module
+        )
+    }
+}
+
+/// Persists cursors so that a restarted process resumes exactly where it stopped.
+///
+/// The cursor must be saved only once the block it belongs to has been fully
+/// processed/persisted, otherwise a crash could skip data on restart.
+pub trait CursorStore {
+    fn load(&self, key: &CursorKey) -> Result<Option<String>, Error>;
+
+    fn save(&self, key: &CursorKey, cursor: &str) -> Result<(), Error>;
+
+    /// Forgets the cursor so that the next run starts from the start block again,
+    /// deleting a cursor that does not exist is not an error.
+    fn delete(&self, key: &CursorKey) -> Result<(), Error>;
+}
+
+/// Stores each cursor in its own file under `directory`, the file is replaced
+/// atomically on every save (write to temporary file, fsync, rename) so that a
+/// crash can never leave a partially written cursor behind.
+#[derive(Clone, Debug)]
+pub struct FileCursorStore {
+    directory: PathBuf,
+    extension: String,
+}
+
+impl FileCursorStore {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        FileCursorStore {
+            directory: directory.into(),
+            extension: "cursor".to_string(),
+        }
+    }
+
+    /// Uses `extension` instead of `cursor` for the files, to store other values
+    /// keyed by [CursorKey] next to the cursors (module hashes for example).
+    pub fn with_extension<E: Into<String>>(mut self, extension: E) -> Self {
+        self.extension = extension.into();
+        self
+    }
+
+    /// Each part of `key` is percent-encoded (`_` included) so that distinct keys
+    /// always map to distinct file names.
+    pub fn path(&self, key: &CursorKey) -> PathBuf {
+        self.directory.join(format!(
+            "{}__{}__{}.{}",
+            encode(&key.endpoint),
+            encode(&key.package),
+            encode(&key.output_module),
+            self.extension
+        ))
+    }
+
+    /// File name used by previous versions, which replaced unsafe characters by `_`
+    /// and could map distinct keys to the same file. Only read to migrate old files.
+    fn legacy_path(&self, key: &CursorKey) -> PathBuf {
+        self.directory.join(format!(
+            "{}__{}__{}.{}",
+            legacy_sanitize(&key.endpoint),
+            legacy_sanitize(&key.package),
+            legacy_sanitize(&key.output_module),
+            self.extension
+        ))
+    }
+}
+
+impl CursorStore for FileCursorStore {
+    fn load(&self, key: &CursorKey) -> Result<Option<String>, Error> {
+        match read_cursor_file(&self.path(key))? {
+            Some(cursor) => Ok(Some(cursor)),
+            None if self.legacy_path(key) != self.path(key) => {
+                read_cursor_file(&self.legacy_path(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, key: &CursorKey, cursor: &str) -> Result<(), Error> {
+        fs::create_dir_all(&self.directory).context(format!(
+            "create cursor directory '{}'",
+            self.directory.display()
+        ))?;
+
+        write_atomically(&self.path(key), cursor.as_bytes())?;
+
+        // The file under the new name now supersedes the legacy one
+        if self.legacy_path(key) != self.path(key) {
+            remove_cursor_file(&self.legacy_path(key))?;
+        }
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &CursorKey) -> Result<(), Error> {
+        remove_cursor_file(&self.path(key))?;
+        remove_cursor_file(&self.legacy_path(key))
+    }
+}
+
+fn read_cursor_file(path: &Path) -> Result<Option<String>, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            let cursor = content.trim();
+            if cursor.is_empty() {
+                return Ok(None);
+            }
+
+            Ok(Some(cursor.to_string()))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context(format!("read cursor file '{}'", path.display())),
+    }
+}
+
+fn remove_cursor_file(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context(format!("delete cursor file '{}'", path.display())),
+    }
+}
+
+/// Refuses to resume from a cursor persisted for `key` unless it was recorded along
+/// with `output_module_hash`, the cursor would otherwise point into the output of
+/// what is effectively another module.
+pub fn check_module_hash(
+    key: &CursorKey,
+    recorded: Option<&str>,
+    output_module_hash: &str,
+) -> Result<(), Error> {
+    match recorded {
+        Some(recorded) if recorded == output_module_hash => Ok(()),
+        Some(recorded) => Err(format_err!(
+            "output module hash changed from {} to {} since the cursor was persisted, refusing to resume ({}), use `cursor reset` to start over",
+            recorded,
+            output_module_hash,
+            key
+        )),
+        None => Err(format_err!(
+            "no output module hash was recorded along with the persisted cursor, refusing to resume ({}), use `cursor reset` to start over",
+            key
+        )),
+    }
+}
+
+/// Shortens `cursor` to its first characters for logging, a full cursor is long and
+/// lets anyone holding it resume the stream.
+pub fn redact_cursor(cursor: &str) -> String {
+    const VISIBLE_CHARS: usize = 8;
+
+    match cursor.char_indices().nth(VISIBLE_CHARS) {
+        _ if cursor.is_empty() => "none".to_string(),
+        Some((end, _)) => format!("{}…", &cursor[..end]),
+        None => cursor.to_string(),
+    }
+}
+
+/// Each write goes through its own temporary file in the destination directory, so
+/// that concurrent writers of the same path never rename each other's partial data.
+pub(crate) fn write_atomically(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut file = NamedTempFile::new_in(directory).context(format!(
+        "create temporary file in '{}'",
+        directory.display()
+    ))?;
+    let tmp_path = file.path().to_path_buf();
+    file.write_all(content)
+        .context(format!("write temporary file '{}'", tmp_path.display()))?;
+    file.as_file()
+        .sync_all()
+        .context(format!("sync temporary file '{}'", tmp_path.display()))?;
+
+    file.persist(path).map_err(|e| e.error).context(format!(
+        "rename '{}' to '{}'",
+        tmp_path.display(),
+        path.display()
+    ))?;
+
+    // The rename itself is only durable once the parent directory entry is flushed
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .context(format!("sync directory '{}'", parent.display()))?;
+    }
+
+    Ok(())
+}
+
+fn encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'@' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn legacy_sanitize(input: &str) -> String {
+    input
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' | '@' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_cursor_store_roundtrip() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path().join("cursors"));
+        let key = CursorKey::new(
+            "https://mainnet.eth.streamingfast.io:443",
+            "pkg@v1.0.0",
+            "map_events",
+        );
+
+        assert_eq!(store.load(&key).unwrap(), None);
+
+        store.save(&key, "cursor-1").unwrap();
+        store.save(&key, "cursor-2").unwrap();
+        assert_eq!(store.load(&key).unwrap(), Some("cursor-2".to_string()));
+
+        store.delete(&key).unwrap();
+        assert_eq!(store.load(&key).unwrap(), None);
+        store.delete(&key).unwrap();
+    }
+
+    #[test]
+    fn file_cursor_store_missing_key() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path().join("missing"));
+        let key = CursorKey::new("localhost:9000", "pkg.spkg", "map_events");
+        let other = CursorKey::new("localhost:9000", "pkg.spkg", "map_transfers");
+
+        // Neither the directory nor the file exist yet
+        assert_eq!(store.load(&key).unwrap(), None);
+        store.delete(&key).unwrap();
+
+        store.save(&key, "cursor-1").unwrap();
+        assert_eq!(store.load(&other).unwrap(), None);
+        store.delete(&other).unwrap();
+        assert_eq!(store.load(&key).unwrap(), Some("cursor-1".to_string()));
+    }
+
+    #[test]
+    fn file_cursor_store_keys_never_collide() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path());
+        let keys = [
+            CursorKey::new("https://host:443", "pkg", "map_events"),
+            CursorKey::new("https___host_443", "pkg", "map_events"),
+            CursorKey::new("https%3A%2F%2Fhost%3A443", "pkg", "map_events"),
+            CursorKey::new("host", "pkg__map", "events"),
+            CursorKey::new("host__pkg", "map", "events"),
+        ];
+
+        for (i, key) in keys.iter().enumerate() {
+            store.save(key, &format!("cursor-{}", i)).unwrap();
+        }
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(store.load(key).unwrap(), Some(format!("cursor-{}", i)));
+        }
+    }
+
+    #[test]
+    fn file_cursor_store_migrates_legacy_file_name() {
+        let directory = tempfile::tempdir().unwrap();
+        let store = FileCursorStore::new(directory.path());
+        let key = CursorKey::new("https://host:443", "pkg.spkg", "map_events");
+        let legacy = directory
+            .path()
+            .join("https___host_443__pkg.spkg__map_events.cursor");
+        fs::write(&legacy, "cursor-1").unwrap();
+
+        assert_eq!(store.load(&key).unwrap(), Some("cursor-1".to_string()));
+
+        store.save(&key, "cursor-2").unwrap();
+        assert!(!legacy.exists());
+        assert_eq!(store.load(&key).unwrap(), Some("cursor-2".to_string()));
+    }
+
+    #[test]
+    fn concurrent_atomic_writes_never_mix() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("shared");
+        let contents: Vec<Vec<u8>> = (0..8u8).map(|i| vec![b'a' + i; 64 * 1024]).collect();
+
+        std::thread::scope(|scope| {
+            for content in &contents {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        write_atomically(path, content).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert!(contents.contains(&fs::read(&path).unwrap()));
+        // No temporary file is left behind
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn redacts_cursor() {
+        assert_eq!(redact_cursor(""), "none");
+        assert_eq!(redact_cursor("cursor-1"), "cursor-1");
+        assert_eq!(
+            redact_cursor("Kxo0Xc6rWhhWrbl2Z2VQZ6WwLpcyBlxnUQnjIBdJ"),
+            "Kxo0Xc6r…"
+        );
+    }
+}
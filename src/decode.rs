@@ -0,0 +1,132 @@
+use anyhow::{format_err, Context, Error};
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use prost_types::Any;
+
+use crate::pb::sf::substreams::v1::Package;
+
+/// Decodes module outputs at runtime using the Protobuf definitions shipped in the
+/// package (`Package.proto_files`), no code generation is required.
+#[derive(Clone, Debug)]
+pub struct OutputDecoder {
+    pool: DescriptorPool,
+}
+
+impl OutputDecoder {
+    pub fn from_package(package: &Package) -> Result<Self, Error> {
+        // Starts from the global pool which contains the Google well-known types, packages
+        // usually ship them too in which case they are skipped as duplicates.
+        let mut pool = DescriptorPool::global();
+        pool.add_file_descriptor_protos(package.proto_files.iter().cloned())
+            .context("build descriptor pool from package proto files")?;
+
+        Ok(OutputDecoder { pool })
+    }
+
+    pub fn pool(&self) -> &DescriptorPool {
+        &self.pool
+    }
+
+    /// Returns the descriptor of the message referenced by `type_url`, in the
+    /// `type.googleapis.com/<message full name>` form or just the message full name.
+    pub fn message_descriptor(&self, type_url: &str) -> Option<MessageDescriptor> {
+        let name = type_url
+            .rsplit_once('/')
+            .map(|(_, name)| name)
+            .unwrap_or(type_url);
+
+        self.pool.get_message_by_name(name)
+    }
+
+    pub fn decode(&self, output: &Any) -> Result<DynamicMessage, Error> {
+        let descriptor = self.message_descriptor(&output.type_url).ok_or_else(|| {
+            format_err!(
+                "message type '{}' is not defined in the package",
+                output.type_url
+            )
+        })?;
+
+        DynamicMessage::decode(descriptor, output.value.as_slice())
+            .context(format!("decode output of type '{}'", output.type_url))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use prost_reflect::Value;
+    use prost_types::{
+        field_descriptor_proto::{Label, Type},
+        DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+    };
+
+    use super::*;
+
+    fn field(name: &str, number: i32, r#type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            json_name: Some(name.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// A package defining `test.Transfer { string from = 1; uint64 amount = 2; }`.
+    pub(crate) fn package() -> Package {
+        Package {
+            proto_files: vec![FileDescriptorProto {
+                name: Some("test.proto".to_string()),
+                package: Some("test".to_string()),
+                message_type: vec![DescriptorProto {
+                    name: Some("Transfer".to_string()),
+                    field: vec![
+                        field("from", 1, Type::String),
+                        field("amount", 2, Type::Uint64),
+                    ],
+                    ..Default::default()
+                }],
+                syntax: Some("proto3".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// `test.Transfer { from: "alice", amount: 42 }`
+    pub(crate) fn transfer() -> Any {
+        let mut value = vec![0x0a, 0x05];
+        value.extend_from_slice(b"alice");
+        value.extend_from_slice(&[0x10, 0x2a]);
+
+        Any {
+            type_url: "type.googleapis.com/test.Transfer".to_string(),
+            value,
+        }
+    }
+
+    #[test]
+    fn decodes_output_using_package_proto_files() {
+        let decoder = OutputDecoder::from_package(&package()).unwrap();
+        let message = decoder.decode(&transfer()).unwrap();
+
+        assert_eq!(
+            message.get_field_by_name("from").unwrap().as_ref(),
+            &Value::String("alice".to_string())
+        );
+        assert_eq!(
+            message.get_field_by_name("amount").unwrap().as_ref(),
+            &Value::U64(42)
+        );
+    }
+
+    #[test]
+    fn unknown_type_url() {
+        let decoder = OutputDecoder::from_package(&package()).unwrap();
+        let output = Any {
+            type_url: "type.googleapis.com/test.Unknown".to_string(),
+            value: vec![],
+        };
+
+        assert!(decoder.decode(&output).is_err());
+    }
+}
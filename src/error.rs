@@ -0,0 +1,194 @@
+use std::{collections::HashMap, time::Duration};
+
+use thiserror::Error;
+use tonic::{Code, Status};
+
+use crate::pb::sf::substreams::rpc::v2;
+
+/// Errors reported by the Substreams server that end a `SubstreamsStream`.
+#[derive(Debug, Error)]
+pub enum SubstreamsError {
+    /// The server reported an unrecoverable error while executing a module (a panic
+    /// in the module's code for example). Retrying would fail the same way.
+    #[error("module '{module}' failed: {reason}{}", format_logs(.logs, *.logs_truncated))]
+    Fatal {
+        module: String,
+        reason: String,
+        logs: Vec<String>,
+        logs_truncated: bool,
+    },
+
+    /// The server answered with a status classified as [ErrorClass::Fatal] by the
+    /// stream's [ErrorClassifier].
+    #[error("non-retryable {} error: {}", .0.code(), .0.message())]
+    NonRetryable(Status),
+
+    /// Every retry allowed by the backoff policy failed.
+    #[error("backoff requested to stop retrying, quitting (last error: {last_error})")]
+    RetriesExhausted { last_error: String },
+}
+
+impl From<v2::Error> for SubstreamsError {
+    fn from(error: v2::Error) -> Self {
+        SubstreamsError::Fatal {
+            module: error.module,
+            reason: error.reason,
+            logs: error.logs,
+            logs_truncated: error.logs_truncated,
+        }
+    }
+}
+
+/// How a failed request should be handled by the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Transient error, the request is retried after the backoff delay.
+    Retryable,
+    /// Retrying would fail the same way, the stream ends with the error.
+    Fatal,
+    /// The server is throttling us, the request is retried after the delay hinted
+    /// by the server (if any and longer than the backoff delay).
+    RateLimited,
+}
+
+/// Decides, based on its `tonic::Code`, how a failed request is handled. Codes that
+/// were not explicitly classified are retryable.
+#[derive(Clone, Debug)]
+pub struct ErrorClassifier {
+    classes: HashMap<Code, ErrorClass>,
+}
+
+impl Default for ErrorClassifier {
+    fn default() -> Self {
+        ErrorClassifier::retry_all()
+            .with(Code::Unauthenticated, ErrorClass::Fatal)
+            .with(Code::PermissionDenied, ErrorClass::Fatal)
+            .with(Code::InvalidArgument, ErrorClass::Fatal)
+            .with(Code::FailedPrecondition, ErrorClass::Fatal)
+            .with(Code::Unimplemented, ErrorClass::Fatal)
+            .with(Code::ResourceExhausted, ErrorClass::RateLimited)
+    }
+}
+
+impl ErrorClassifier {
+    /// A classifier retrying every code, use [ErrorClassifier::with] to classify
+    /// codes differently.
+    pub fn retry_all() -> Self {
+        ErrorClassifier {
+            classes: HashMap::new(),
+        }
+    }
+
+    pub fn with(mut self, code: Code, class: ErrorClass) -> Self {
+        self.classes.insert(code, class);
+        self
+    }
+
+    pub fn classify(&self, status: &Status) -> ErrorClass {
+        self.classes
+            .get(&status.code())
+            .copied()
+            .unwrap_or(ErrorClass::Retryable)
+    }
+}
+
+/// Extracts the delay the server asked us to wait before retrying, from the
+/// `grpc-retry-pushback-ms` or `retry-after` (in seconds) metadata.
+pub fn retry_after(status: &Status) -> Option<Duration> {
+    let metadata = status.metadata();
+
+    if let Some(value) = metadata.get("grpc-retry-pushback-ms") {
+        if let Some(millis) = value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().parse::<u64>().ok())
+        {
+            return Some(Duration::from_millis(millis));
+        }
+    }
+
+    metadata
+        .get("retry-after")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+fn format_logs(logs: &[String], logs_truncated: bool) -> String {
+    if logs.is_empty() {
+        return "".to_string();
+    }
+
+    let mut output = String::from("\nModule logs:");
+    for line in logs {
+        output.push_str("\n  ");
+        output.push_str(line);
+    }
+
+    if logs_truncated {
+        output.push_str("\n  <logs truncated>");
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::metadata::MetadataMap;
+
+    use super::*;
+
+    #[test]
+    fn default_classifier() {
+        let classifier = ErrorClassifier::default();
+
+        assert_eq!(
+            classifier.classify(&Status::unauthenticated("bad token")),
+            ErrorClass::Fatal
+        );
+        assert_eq!(
+            classifier.classify(&Status::failed_precondition("bad module graph")),
+            ErrorClass::Fatal
+        );
+        assert_eq!(
+            classifier.classify(&Status::resource_exhausted("quota")),
+            ErrorClass::RateLimited
+        );
+        assert_eq!(
+            classifier.classify(&Status::unavailable("restarting")),
+            ErrorClass::Retryable
+        );
+    }
+
+    #[test]
+    fn classifier_overrides() {
+        let classifier = ErrorClassifier::default()
+            .with(Code::ResourceExhausted, ErrorClass::Fatal)
+            .with(Code::Internal, ErrorClass::Fatal);
+
+        assert_eq!(
+            classifier.classify(&Status::resource_exhausted("quota")),
+            ErrorClass::Fatal
+        );
+        assert_eq!(
+            classifier.classify(&Status::internal("boom")),
+            ErrorClass::Fatal
+        );
+    }
+
+    #[test]
+    fn retry_after_hints() {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("retry-after", "30".parse().unwrap());
+        let status = Status::with_metadata(Code::ResourceExhausted, "slow down", metadata);
+        assert_eq!(retry_after(&status), Some(Duration::from_secs(30)));
+
+        let mut metadata = MetadataMap::new();
+        metadata.insert("retry-after", "30".parse().unwrap());
+        metadata.insert("grpc-retry-pushback-ms", "1500".parse().unwrap());
+        let status = Status::with_metadata(Code::ResourceExhausted, "slow down", metadata);
+        assert_eq!(retry_after(&status), Some(Duration::from_millis(1500)));
+
+        assert_eq!(retry_after(&Status::resource_exhausted("quota")), None);
+    }
+}
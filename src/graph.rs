@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use anyhow::Error;
+
+use crate::package::{ancestors, find_module};
+use crate::pb::sf::substreams::v1::{
+    module::{
+        input::{store::Mode, Input},
+        Kind,
+    },
+    Module, Modules,
+};
+
+/// Module dependency graph, of the whole package or of the modules needed to
+/// compute an output module, exported as Graphviz DOT or Mermaid.
+///
+/// Building the graph fails when a module depends on an unknown module or when the
+/// dependencies form a cycle.
+pub struct ModuleGraph<'a> {
+    modules: Vec<&'a Module>,
+}
+
+enum Node<'a> {
+    Module(&'a str),
+    Source(&'a str),
+    Params(&'a str, &'a str),
+}
+
+struct Edge<'a> {
+    from: Node<'a>,
+    to: &'a str,
+    label: Option<&'static str>,
+}
+
+impl<'a> ModuleGraph<'a> {
+    pub fn new(modules: &'a Modules, output_module: Option<&str>) -> Result<Self, Error> {
+        let selected: Vec<&Module> = match output_module {
+            None => {
+                for module in &modules.modules {
+                    ancestors(modules, &module.name)?;
+                }
+
+                modules.modules.iter().collect()
+            }
+            Some(output_module) => {
+                find_module(modules, output_module)?;
+                let mut names = ancestors(modules, output_module)?;
+                names.insert(output_module.to_string());
+
+                modules
+                    .modules
+                    .iter()
+                    .filter(|m| names.contains(&m.name))
+                    .collect()
+            }
+        };
+
+        Ok(ModuleGraph { modules: selected })
+    }
+
+    pub fn module_names(&self) -> Vec<&str> {
+        self.modules.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    fn edges(&self) -> Vec<Edge<'a>> {
+        let mut edges = vec![];
+
+        for module in &self.modules {
+            for input in module
+                .inputs
+                .iter()
+                .filter_map(|input| input.input.as_ref())
+            {
+                let (from, label) = match input {
+                    Input::Source(source) => (Node::Source(&source.r#type), None),
+                    Input::Map(map) => (Node::Module(&map.module_name), None),
+                    Input::Store(store) => (
+                        Node::Module(&store.module_name),
+                        Some(match store.mode() {
+                            Mode::Deltas => "deltas",
+                            _ => "get",
+                        }),
+                    ),
+                    Input::Params(params) => (Node::Params(&module.name, &params.value), None),
+                };
+
+                edges.push(Edge {
+                    from,
+                    to: &module.name,
+                    label,
+                });
+            }
+        }
+
+        edges
+    }
+
+    fn sources(&self) -> BTreeSet<&'a str> {
+        self.edges()
+            .into_iter()
+            .filter_map(|edge| match edge.from {
+                Node::Source(source) => Some(source),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph modules {\n  rankdir=LR;\n");
+
+        for source in self.sources() {
+            writeln!(out, "  {} [shape=plaintext];", dot_id(source))
+                .expect("writing to a String never fails");
+        }
+
+        for module in &self.modules {
+            let shape = match module.kind {
+                Some(Kind::KindStore(_)) => "cylinder",
+                Some(Kind::KindBlockIndex(_)) => "hexagon",
+                _ => "box",
+            };
+            writeln!(out, "  {} [shape={}];", dot_id(&module.name), shape)
+                .expect("writing to a String never fails");
+        }
+
+        for edge in self.edges() {
+            let from = match edge.from {
+                Node::Module(name) | Node::Source(name) => dot_id(name),
+                Node::Params(module, value) => {
+                    let id = dot_id(&format!("{}:params", module));
+                    writeln!(out, "  {} [shape=note, label={}];", id, dot_id(value))
+                        .expect("writing to a String never fails");
+                    id
+                }
+            };
+
+            match edge.label {
+                Some(label) => writeln!(
+                    out,
+                    "  {} -> {} [label=\"{}\"];",
+                    from,
+                    dot_id(edge.to),
+                    label
+                ),
+                None => writeln!(out, "  {} -> {};", from, dot_id(edge.to)),
+            }
+            .expect("writing to a String never fails");
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    /// Node ids are generated (`s0` for sources, `m0` for modules, `p0` for params),
+    /// names only appear in labels: they could collide once sanitized or be Mermaid
+    /// keywords (`end`).
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("graph LR\n");
+        let mut ids = MermaidIds::default();
+
+        for source in self.sources() {
+            writeln!(out, "  {}[/\"{}\"/]", ids.get("s", source), escape(source))
+                .expect("writing to a String never fails");
+        }
+
+        for module in &self.modules {
+            let id = ids.get("m", &module.name);
+            let name = escape(&module.name);
+            match module.kind {
+                Some(Kind::KindStore(_)) => writeln!(out, "  {}[(\"{}\")]", id, name),
+                Some(Kind::KindBlockIndex(_)) => writeln!(out, "  {}{{{{\"{}\"}}}}", id, name),
+                _ => writeln!(out, "  {}[\"{}\"]", id, name),
+            }
+            .expect("writing to a String never fails");
+        }
+
+        for edge in self.edges() {
+            let from = match edge.from {
+                Node::Module(name) => ids.get("m", name),
+                Node::Source(name) => ids.get("s", name),
+                Node::Params(module, value) => {
+                    let id = ids.get("p", module);
+                    writeln!(out, "  {}>\"params: {}\"]", id, escape(value))
+                        .expect("writing to a String never fails");
+                    id
+                }
+            };
+
+            let to = ids.get("m", edge.to);
+            match edge.label {
+                Some(label) => writeln!(out, "  {} -->|{}| {}", from, label, to),
+                None => writeln!(out, "  {} --> {}", from, to),
+            }
+            .expect("writing to a String never fails");
+        }
+
+        out
+    }
+}
+
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Numbers nodes per prefix in order of first appearance.
+#[derive(Default)]
+struct MermaidIds<'a> {
+    ids: HashMap<(&'static str, &'a str), String>,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl<'a> MermaidIds<'a> {
+    fn get(&mut self, prefix: &'static str, name: &'a str) -> String {
+        let counts = &mut self.counts;
+        self.ids
+            .entry((prefix, name))
+            .or_insert_with(|| {
+                let count = counts.entry(prefix).or_default();
+                *count += 1;
+                format!("{}{}", prefix, *count - 1)
+            })
+            .clone()
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('"', "#quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::tests::{input, map_module};
+    use crate::pb::sf::substreams::v1::module::{
+        input::{Map, Params, Source, Store},
+        KindStore,
+    };
+
+    fn modules() -> Modules {
+        let mut store = map_module(
+            "store_pools",
+            vec![Input::Source(Source {
+                r#type: "sf.ethereum.type.v2.Block".to_string(),
+            })],
+        );
+        store.kind = Some(Kind::KindStore(KindStore {
+            update_policy: 1,
+            value_type: "string".to_string(),
+        }));
+
+        Modules {
+            modules: vec![
+                store,
+                map_module(
+                    "map_swaps",
+                    vec![
+                        Input::Params(Params {
+                            value: "fee=3000".to_string(),
+                        }),
+                        Input::Store(Store {
+                            module_name: "store_pools".to_string(),
+                            mode: Mode::Get as i32,
+                        }),
+                        Input::Store(Store {
+                            module_name: "store_pools".to_string(),
+                            mode: Mode::Deltas as i32,
+                        }),
+                    ],
+                ),
+                map_module(
+                    "map_unrelated",
+                    vec![Input::Source(Source {
+                        r#type: "sf.ethereum.type.v2.Block".to_string(),
+                    })],
+                ),
+            ],
+            binaries: vec![],
+        }
+    }
+
+    #[test]
+    fn exports_dot() {
+        let modules = modules();
+        let graph = ModuleGraph::new(&modules, Some("map_swaps")).unwrap();
+
+        assert_eq!(graph.module_names(), vec!["store_pools", "map_swaps"]);
+        assert_eq!(
+            graph.to_dot(),
+            r#"digraph modules {
+  rankdir=LR;
+  "sf.ethereum.type.v2.Block" [shape=plaintext];
+  "store_pools" [shape=cylinder];
+  "map_swaps" [shape=box];
+  "sf.ethereum.type.v2.Block" -> "store_pools";
+  "map_swaps:params" [shape=note, label="fee=3000"];
+  "map_swaps:params" -> "map_swaps";
+  "store_pools" -> "map_swaps" [label="get"];
+  "store_pools" -> "map_swaps" [label="deltas"];
+}
+"#
+        );
+    }
+
+    #[test]
+    fn exports_mermaid() {
+        let modules = modules();
+        let graph = ModuleGraph::new(&modules, None).unwrap();
+
+        assert_eq!(
+            graph.module_names(),
+            vec!["store_pools", "map_swaps", "map_unrelated"]
+        );
+        assert_eq!(
+            graph.to_mermaid(),
+            r#"graph LR
+  s0[/"sf.ethereum.type.v2.Block"/]
+  m0[("store_pools")]
+  m1["map_swaps"]
+  m2["map_unrelated"]
+  s0 --> m0
+  p0>"params: fee=3000"]
+  p0 --> m1
+  m0 -->|get| m1
+  m0 -->|deltas| m1
+  s0 --> m2
+"#
+        );
+    }
+
+    #[test]
+    fn mermaid_ids_never_collide() {
+        let block = || {
+            vec![Input::Source(Source {
+                r#type: "sf.ethereum.type.v2.Block".to_string(),
+            })]
+        };
+        let modules = Modules {
+            modules: vec![
+                map_module("map-a", block()),
+                map_module(
+                    "map_a",
+                    vec![Input::Params(Params {
+                        value: "x".to_string(),
+                    })],
+                ),
+                map_module("map_a_params", block()),
+                map_module(
+                    "end",
+                    vec![Input::Map(Map {
+                        module_name: "map_a".to_string(),
+                    })],
+                ),
+            ],
+            binaries: vec![],
+        };
+        let graph = ModuleGraph::new(&modules, None).unwrap();
+
+        assert_eq!(
+            graph.to_mermaid(),
+            r#"graph LR
+  s0[/"sf.ethereum.type.v2.Block"/]
+  m0["map-a"]
+  m1["map_a"]
+  m2["map_a_params"]
+  m3["end"]
+  s0 --> m0
+  p0>"params: x"]
+  p0 --> m1
+  s0 --> m2
+  m1 --> m3
+"#
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_graphs() {
+        let mut modules = modules();
+        modules.modules[0].inputs.push(input(Input::Map(Map {
+            module_name: "map_swaps".to_string(),
+        })));
+        assert!(ModuleGraph::new(&modules, None)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("cycle"));
+
+        let mut modules = self::modules();
+        modules.modules[2].inputs.push(input(Input::Map(Map {
+            module_name: "map_missing".to_string(),
+        })));
+        assert!(ModuleGraph::new(&modules, Some("map_swaps")).is_ok());
+        assert_eq!(
+            ModuleGraph::new(&modules, None).err().unwrap().to_string(),
+            "module 'map_unrelated' depends on unknown module 'map_missing'"
+        );
+    }
+}
@@ -0,0 +1,384 @@
+use std::fmt::Display;
+
+use serde_json::{json, Value};
+
+use crate::package::module_hash;
+use crate::pb::sf::substreams::v1::{
+    module::{
+        block_filter::Query,
+        input::{store::Mode, Input},
+        Kind,
+    },
+    Module, Package,
+};
+
+/// What a module is, as described by `package inspect`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleSummary {
+    pub name: String,
+    pub kind: ModuleKind,
+    pub inputs: Vec<ModuleInput>,
+    pub output_type: Option<String>,
+    pub initial_block: u64,
+    pub block_filter: Option<String>,
+    pub doc: Option<String>,
+    /// `None` when the module graph is invalid (dangling reference or cycle)
+    pub hash: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModuleKind {
+    Map,
+    Store {
+        update_policy: String,
+        value_type: String,
+    },
+    BlockIndex,
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ModuleInput {
+    Source(String),
+    Map(String),
+    Store { module: String, mode: String },
+    Params(String),
+}
+
+/// Summarizes every module of the package, in declaration order, with its doc
+/// taken from `Package.module_meta`.
+pub fn module_summaries(package: &Package) -> Vec<ModuleSummary> {
+    let Some(modules) = package.modules.as_ref() else {
+        return vec![];
+    };
+
+    modules
+        .modules
+        .iter()
+        .enumerate()
+        .map(|(i, module)| ModuleSummary {
+            name: module.name.clone(),
+            kind: kind(module),
+            inputs: module
+                .inputs
+                .iter()
+                .filter_map(|input| input.input.as_ref())
+                .map(input)
+                .collect(),
+            output_type: output_type(module),
+            initial_block: module.initial_block,
+            block_filter: module
+                .block_filter
+                .as_ref()
+                .map(|filter| match &filter.query {
+                    Some(Query::QueryString(query)) => format!("{}: {}", filter.module, query),
+                    Some(Query::QueryFromParams(_)) => format!("{}: <from params>", filter.module),
+                    None => filter.module.clone(),
+                }),
+            doc: package
+                .module_meta
+                .get(i)
+                .map(|meta| meta.doc.trim().to_string())
+                .filter(|doc| !doc.is_empty()),
+            hash: module_hash(modules, &module.name).ok(),
+        })
+        .collect()
+}
+
+fn kind(module: &Module) -> ModuleKind {
+    match &module.kind {
+        Some(Kind::KindMap(_)) => ModuleKind::Map,
+        Some(Kind::KindStore(store)) => ModuleKind::Store {
+            update_policy: store
+                .update_policy()
+                .as_str_name()
+                .trim_start_matches("UPDATE_POLICY_")
+                .to_lowercase(),
+            value_type: store.value_type.clone(),
+        },
+        Some(Kind::KindBlockIndex(_)) => ModuleKind::BlockIndex,
+        None => ModuleKind::Unknown,
+    }
+}
+
+fn input(input: &Input) -> ModuleInput {
+    match input {
+        Input::Source(source) => ModuleInput::Source(source.r#type.clone()),
+        Input::Map(map) => ModuleInput::Map(map.module_name.clone()),
+        Input::Store(store) => ModuleInput::Store {
+            module: store.module_name.clone(),
+            mode: match store.mode() {
+                Mode::Deltas => "deltas".to_string(),
+                _ => "get".to_string(),
+            },
+        },
+        Input::Params(params) => ModuleInput::Params(params.value.clone()),
+    }
+}
+
+fn output_type(module: &Module) -> Option<String> {
+    let output_type = match (&module.output, &module.kind) {
+        (Some(output), _) if !output.r#type.is_empty() => output.r#type.clone(),
+        (_, Some(Kind::KindMap(map))) => map.output_type.clone(),
+        (_, Some(Kind::KindBlockIndex(index))) => index.output_type.clone(),
+        (_, Some(Kind::KindStore(store))) => store.value_type.clone(),
+        _ => return None,
+    };
+
+    Some(output_type).filter(|t| !t.is_empty())
+}
+
+impl Display for ModuleKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleKind::Map => write!(f, "map"),
+            ModuleKind::Store {
+                update_policy,
+                value_type,
+            } => write!(
+                f,
+                "store (update policy: {}, value type: {})",
+                update_policy, value_type
+            ),
+            ModuleKind::BlockIndex => write!(f, "block index"),
+            ModuleKind::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Display for ModuleInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleInput::Source(source) => write!(f, "source: {}", source),
+            ModuleInput::Map(module) => write!(f, "map: {}", module),
+            ModuleInput::Store { module, mode } => write!(f, "store: {} ({})", module, mode),
+            ModuleInput::Params(value) => write!(f, "params: {}", value),
+        }
+    }
+}
+
+impl Display for ModuleSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        writeln!(f, "  Kind: {}", self.kind)?;
+        writeln!(f, "  Initial block: {}", self.initial_block)?;
+        if let Some(output_type) = &self.output_type {
+            writeln!(f, "  Output type: {}", output_type)?;
+        }
+        if let Some(block_filter) = &self.block_filter {
+            writeln!(f, "  Block filter: {}", block_filter)?;
+        }
+        if let Some(hash) = &self.hash {
+            writeln!(f, "  Hash: {}", hash)?;
+        }
+
+        writeln!(f, "  Inputs:")?;
+        for input in &self.inputs {
+            writeln!(f, "    {}", input)?;
+        }
+
+        if let Some(doc) = &self.doc {
+            writeln!(f, "  Doc:")?;
+            for line in doc.lines() {
+                writeln!(f, "    {}", line)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ModuleSummary {
+    pub fn to_json(&self) -> Value {
+        let kind = match &self.kind {
+            ModuleKind::Map => json!({"type": "map"}),
+            ModuleKind::Store {
+                update_policy,
+                value_type,
+            } => json!({
+                "type": "store",
+                "update_policy": update_policy,
+                "value_type": value_type,
+            }),
+            ModuleKind::BlockIndex => json!({"type": "block_index"}),
+            ModuleKind::Unknown => json!({"type": "unknown"}),
+        };
+
+        let inputs: Vec<Value> = self
+            .inputs
+            .iter()
+            .map(|input| match input {
+                ModuleInput::Source(source) => json!({"source": source}),
+                ModuleInput::Map(module) => json!({"map": module}),
+                ModuleInput::Store { module, mode } => json!({"store": module, "mode": mode}),
+                ModuleInput::Params(value) => json!({"params": value}),
+            })
+            .collect();
+
+        json!({
+            "name": self.name,
+            "kind": kind,
+            "inputs": inputs,
+            "output_type": self.output_type,
+            "initial_block": self.initial_block,
+            "block_filter": self.block_filter,
+            "doc": self.doc,
+            "hash": self.hash,
+        })
+    }
+}
+
+/// Machine readable description of the package and its modules.
+pub fn package_json(package: &Package) -> Value {
+    let meta = package.package_meta.first();
+
+    json!({
+        "name": meta.map(|m| m.name.as_str()),
+        "version": meta.map(|m| m.version.as_str()),
+        "doc": meta.map(|m| m.doc.trim()).filter(|doc| !doc.is_empty()),
+        "network": Some(package.network.as_str()).filter(|n| !n.is_empty()),
+        "modules": module_summaries(package)
+            .iter()
+            .map(ModuleSummary::to_json)
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::package::tests::input;
+    use crate::pb::sf::substreams::v1::{
+        module::{
+            self, input::Store, kind_store::UpdatePolicy, BlockFilter, KindBlockIndex, KindMap,
+            KindStore, QueryFromParams,
+        },
+        Binary, ModuleMetadata, Modules, PackageMetadata,
+    };
+
+    fn package() -> Package {
+        Package {
+            modules: Some(Modules {
+                modules: vec![
+                    Module {
+                        name: "store_balances".to_string(),
+                        kind: Some(Kind::KindStore(KindStore {
+                            update_policy: UpdatePolicy::Add as i32,
+                            value_type: "bigint".to_string(),
+                        })),
+                        inputs: vec![input(Input::Source(module::input::Source {
+                            r#type: "sf.ethereum.type.v2.Block".to_string(),
+                        }))],
+                        initial_block: 100,
+                        ..Default::default()
+                    },
+                    Module {
+                        name: "map_balances".to_string(),
+                        kind: Some(Kind::KindMap(KindMap {
+                            output_type: "proto:test.Balances".to_string(),
+                        })),
+                        inputs: vec![
+                            input(Input::Params(module::input::Params {
+                                value: "0xabc".to_string(),
+                            })),
+                            input(Input::Store(Store {
+                                module_name: "store_balances".to_string(),
+                                mode: Mode::Deltas as i32,
+                            })),
+                        ],
+                        output: Some(module::Output {
+                            r#type: "proto:test.Balances".to_string(),
+                        }),
+                        initial_block: 100,
+                        block_filter: Some(BlockFilter {
+                            module: "index_transfers".to_string(),
+                            query: Some(Query::QueryFromParams(QueryFromParams {})),
+                        }),
+                        ..Default::default()
+                    },
+                    Module {
+                        name: "index_transfers".to_string(),
+                        kind: Some(Kind::KindBlockIndex(KindBlockIndex {
+                            output_type: "proto:sf.substreams.index.v1.Keys".to_string(),
+                        })),
+                        ..Default::default()
+                    },
+                ],
+                binaries: vec![Binary {
+                    r#type: "wasm/rust-v1".to_string(),
+                    content: b"\0asm".to_vec(),
+                }],
+            }),
+            module_meta: vec![
+                ModuleMetadata {
+                    package_index: 0,
+                    doc: "Balances per account\n".to_string(),
+                },
+                ModuleMetadata::default(),
+                ModuleMetadata::default(),
+            ],
+            package_meta: vec![PackageMetadata {
+                name: "balances".to_string(),
+                version: "v0.1.0".to_string(),
+                ..Default::default()
+            }],
+            network: "mainnet".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn summarizes_modules() {
+        let summaries = module_summaries(&package());
+
+        assert_eq!(
+            summaries[0].kind,
+            ModuleKind::Store {
+                update_policy: "add".to_string(),
+                value_type: "bigint".to_string()
+            }
+        );
+        assert_eq!(summaries[0].output_type.as_deref(), Some("bigint"));
+        assert_eq!(summaries[0].doc.as_deref(), Some("Balances per account"));
+        assert!(summaries[0].hash.is_some());
+
+        assert_eq!(summaries[1].kind, ModuleKind::Map);
+        assert_eq!(
+            summaries[1]
+                .inputs
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["params: 0xabc", "store: store_balances (deltas)"]
+        );
+        assert_eq!(
+            summaries[1].block_filter.as_deref(),
+            Some("index_transfers: <from params>")
+        );
+        assert_eq!(summaries[1].doc, None);
+
+        assert_eq!(summaries[2].kind, ModuleKind::BlockIndex);
+        assert_eq!(
+            summaries[2].output_type.as_deref(),
+            Some("proto:sf.substreams.index.v1.Keys")
+        );
+    }
+
+    #[test]
+    fn describes_package_as_json() {
+        let json = package_json(&package());
+
+        assert_eq!(json["name"], "balances");
+        assert_eq!(json["version"], "v0.1.0");
+        assert_eq!(json["network"], "mainnet");
+        assert_eq!(
+            json["modules"][0]["kind"],
+            json!({"type": "store", "update_policy": "add", "value_type": "bigint"})
+        );
+        assert_eq!(
+            json["modules"][1]["inputs"],
+            json!([{"params": "0xabc"}, {"store": "store_balances", "mode": "deltas"}])
+        );
+        assert_eq!(json["modules"][2]["initial_block"], 0);
+    }
+}
@@ -0,0 +1,42 @@
+//! Consumes a Substreams module's output with automatic reconnection and hands it to
+//! a [Sink], which persists it along with the cursor needed to resume streaming.
+//!
+//! ```no_run
+//! # async fn run(endpoint: std::sync::Arc<substreams_sink_rust::SubstreamsEndpoint>, package: substreams_sink_rust::pb::sf::substreams::v1::Package) -> Result<(), anyhow::Error> {
+//! use substreams_sink_rust::{run_sink, sink::MemorySink, Sink, SubstreamsStream};
+//!
+//! let mut sink = MemorySink::new();
+//! let stream = SubstreamsStream::builder()
+//!     .endpoint(endpoint)
+//!     .cursor(sink.cursor()?)
+//!     .modules(package.modules)
+//!     .output_module("map_events")
+//!     .build()?;
+//!
+//! run_sink(stream, &mut sink).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod backfill;
+pub mod cursor;
+pub mod decode;
+pub mod error;
+pub mod graph;
+pub mod inspect;
+pub mod metrics;
+pub mod multiplexer;
+pub mod package;
+#[allow(clippy::enum_variant_names)]
+pub mod pb;
+pub mod progress;
+pub mod reorg_buffer;
+pub mod sink;
+pub mod spkg_cache;
+pub mod substreams;
+pub mod substreams_stream;
+
+pub use error::SubstreamsError;
+pub use sink::{run_sink, Sink};
+pub use substreams::SubstreamsEndpoint;
+pub use substreams_stream::{BlockResponse, SubstreamsStream};
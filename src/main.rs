@@ -1,20 +1,53 @@
 use anyhow::{format_err, Context, Error};
 use chrono::DateTime;
-use futures03::StreamExt;
+use clap::Parser;
+use futures03::Stream;
 use lazy_static::lazy_static;
-use pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal};
-use pb::sf::substreams::v1::Package;
 use regex::Regex;
 use semver::Version;
 
 use prost::Message;
-use std::{env, process::exit, sync::Arc};
-use substreams::SubstreamsEndpoint;
-use substreams_stream::{BlockResponse, SubstreamsStream};
-
-mod pb;
-mod substreams;
-mod substreams_stream;
+use std::{
+    fs,
+    io::{stderr, stdout, ErrorKind, IsTerminal, Stderr, Stdout},
+    pin::Pin,
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use substreams_sink_rust::{
+    backfill::Backfill,
+    cursor::{check_module_hash, redact_cursor, CursorKey, CursorStore, FileCursorStore},
+    decode::OutputDecoder,
+    graph::ModuleGraph,
+    inspect::{module_summaries, package_json},
+    metrics::{Metrics, MetricsServer},
+    package::{apply_network, apply_params, find_module, module_hash},
+    pb::sf::substreams::{
+        rpc::v2::{BlockScopedData, BlockUndoSignal, SessionInit},
+        v1::{BlockRef, Package},
+    },
+    progress::{Progress, ProgressSummary, ProgressTracker},
+    reorg_buffer::ReorgBuffer,
+    run_sink,
+    sink::{CursorSink, FileSink, JsonLinesSink, SqliteSink},
+    spkg_cache::{sha256_hex, SpkgCache},
+    substreams::{check_network, check_start_block},
+    BlockResponse, Sink, SubstreamsEndpoint, SubstreamsStream,
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+mod cli;
+
+use cli::{
+    Cli, Command, CursorArgs, CursorCommand, GraphArgs, GraphFormat, InspectArgs, LogFormat,
+    OutputFormat, PackageArgs, PackageCommand, PackageSourceArgs, ReorgBufferMode, RunArgs,
+    SinkArgs,
+};
 
 lazy_static! {
     static ref MODULE_NAME_REGEXP: Regex = Regex::new(r"^([a-zA-Z][a-zA-Z0-9_-]{0,63})$").unwrap();
@@ -24,83 +57,583 @@ const REGISTRY_URL: &str = "https://spkg.io";
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let args = env::args();
-    if args.len() < 4 || args.len() > 5 {
-        println!("usage: stream <endpoint> <spkg> <module> [<start>:<stop>]");
-        println!();
-        println!("<spkg> can either be the full spkg.io link or `spkg_package@version`");
-        println!("Example usage: stream mainnet.injective.streamingfast.io:443 injective-common@v0.2.3 all_events 1:10");
-
-        println!("The environment variable SUBSTREAMS_API_TOKEN must be set also");
-        println!("and should contain a valid Substream API token.");
-        exit(1);
+    let cli = Cli::parse();
+    let progress_line = ProgressLine::default();
+    init_logging(&cli.log_level, cli.log_format, progress_line.clone());
+
+    // Progress is only redrawn in place for plain logs read on a terminal
+    let progress_line = match cli.log_format {
+        LogFormat::Plain if stderr().is_terminal() => Some(progress_line),
+        _ => None,
+    };
+
+    match cli.command {
+        Command::Run(args) => run(*args, progress_line).await,
+        Command::Info(args) => info(args).await,
+        Command::Package(PackageCommand::Inspect(args)) => package_inspect(args).await,
+        Command::Package(PackageCommand::Graph(args)) => package_graph(args).await,
+        Command::Cursor(CursorCommand::Show(args)) => cursor_show(args),
+        Command::Cursor(CursorCommand::Reset(args)) => cursor_reset(args),
     }
+}
 
-    let mut endpoint_url = env::args().nth(1).unwrap();
-    let package_file = env::args().nth(2).unwrap();
-    let module_name = env::args().nth(3).unwrap();
+/// Logs are written to standard error, standard output only carries module outputs.
+fn init_logging(filter: &str, format: LogFormat, progress_line: ProgressLine) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .with_writer(progress_line)
+        .with_ansi(stderr().is_terminal());
 
-    if !endpoint_url.starts_with("http") {
-        endpoint_url = format!("{}://{}", "https", endpoint_url);
+    match format {
+        LogFormat::Plain => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
     }
+}
 
-    let token_env = env::var("SUBSTREAMS_API_TOKEN").unwrap_or("".to_string());
-    let mut token: Option<String> = None;
-    if token_env.len() > 0 {
-        token = Some(token_env);
+async fn run(args: RunArgs, progress_line: Option<ProgressLine>) -> Result<(), Error> {
+    // Records cannot be taken back from standard output once printed
+    if args.backfill_stop_block.is_some() && args.output == OutputFormat::Jsonl {
+        return Err(format_err!(
+            "--output jsonl cannot be used with a backfill, the blocks delivered since the last checkpoint would be printed again after a restart"
+        ));
     }
 
-    let package = read_package(&package_file).await?;
-    let block_range = read_block_range(&package, &module_name)?;
+    let endpoint_url = args.endpoint.url();
+    let token = args
+        .endpoint
+        .token
+        .clone()
+        .filter(|token| !token.is_empty());
+
+    let mut package = read_package(&args.package).await?;
+    apply_network(&mut package, args.network.as_deref())?;
+
+    let modules = package
+        .modules
+        .as_mut()
+        .ok_or_else(|| format_err!("package has no modules"))?;
+    apply_params(modules, &args.params)?;
+
+    let module = find_module(modules, &args.module)?;
+    let output_module_hash = module_hash(modules, &args.module)?;
+    info!(module = %args.module, hash = %output_module_hash, "Output module hash");
+
+    let (mut start_block, stop_block) =
+        args.range.unwrap_or_default().resolve(module.initial_block);
     let endpoint = Arc::new(SubstreamsEndpoint::new(&endpoint_url, token).await?);
 
-    let cursor: Option<String> = load_persisted_cursor()?;
+    if !args.skip_endpoint_check {
+        start_block = check_endpoint(
+            &endpoint,
+            &package.network,
+            start_block,
+            args.clamp_start_block,
+        )
+        .await?;
+    }
 
-    let mut stream = SubstreamsStream::new(
-        endpoint,
-        cursor,
-        package.modules,
-        module_name.to_string(),
-        block_range.0,
-        block_range.1,
-    );
+    let cursor_key = CursorKey::new(&endpoint_url, &args.package.package, &args.module);
+    let sink = open_sink(&args.sink, &cursor_key, &output_module_hash)?;
+    let decoder = OutputDecoder::from_package(&package)?;
+    let mut sink = PrintingSink {
+        inner: sink,
+        printer: match args.output {
+            OutputFormat::Text => Printer::Text(decoder),
+            OutputFormat::Jsonl => Printer::JsonLines(JsonLinesSink::new(stdout(), decoder)),
+        },
+        progress: ProgressReporter::new(progress_line),
+        out_of_order: args.backfill_out_of_order,
+    };
+
+    let cursor = sink.cursor()?;
+    match &cursor {
+        Some(cursor) => info!(
+            key = %cursor_key,
+            cursor = %redact_cursor(cursor),
+            "Resuming from persisted cursor"
+        ),
+        None => info!(
+            key = %cursor_key,
+            start_block,
+            "No persisted cursor found, starting from start block"
+        ),
+    }
+
+    let progress_interval = match (args.progress_interval, sink.progress.line.is_some()) {
+        (Some(seconds), _) => seconds,
+        (None, true) => 1,
+        (None, false) => 30,
+    };
 
-    loop {
-        match stream.next().await {
-            None => {
-                println!("Stream consumed");
-                break;
+    let mut builder = SubstreamsStream::builder()
+        .endpoint(endpoint)
+        .modules(package.modules)
+        .output_module(&args.module)
+        .start_block(start_block)
+        .stop_block(stop_block)
+        .final_blocks_only(args.final_only)
+        .progress_report_interval(Duration::from_secs(progress_interval));
+
+    if let Some(address) = args.metrics_addr {
+        let metrics = Arc::new(Metrics::new()?);
+        let server = MetricsServer::bind(address).await?;
+        info!("Serving metrics on http://{}/metrics", server.local_addr()?);
+
+        let served = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = server.serve(served).await {
+                error!("Metrics server stopped: {:#}", err);
             }
-            Some(Ok(BlockResponse::New(data))) => {
-                process_block_scoped_data(&data)?;
-                persist_cursor(data.cursor)?;
+        });
+        builder = builder.metrics(metrics);
+    }
+
+    let builder = match (args.backfill_stop_block, args.backfill_segments) {
+        (Some(backfill_stop_block), Some(segments)) => {
+            let backfill_start_block = u64::try_from(start_block).map_err(|_| {
+                format_err!(
+                    "a backfill needs an absolute start block, got {}",
+                    start_block
+                )
+            })?;
+            if backfill_stop_block <= backfill_start_block {
+                return Err(format_err!(
+                    "backfill stop block #{} must be greater than the start block #{}",
+                    backfill_stop_block,
+                    backfill_start_block
+                ));
             }
-            Some(Ok(BlockResponse::Undo(undo_signal))) => {
-                process_block_undo_signal(&undo_signal)?;
-                persist_cursor(undo_signal.last_valid_cursor)?;
+            if stop_block != 0 && backfill_stop_block > stop_block {
+                return Err(format_err!(
+                    "backfill stop block #{} is beyond the stop block #{} of the range",
+                    backfill_stop_block,
+                    stop_block
+                ));
             }
-            Some(Err(err)) => {
-                println!();
-                println!("Stream terminated with error");
-                println!("{:?}", err);
-                exit(1);
+
+            let backfill = Backfill::new(builder, checkpoint_store(&args.sink), cursor_key)
+                .range(backfill_start_block, backfill_stop_block)
+                .segments(segments as usize)
+                .buffer_size(args.backfill_buffer_size);
+
+            match backfill.run(&mut sink).await {
+                Ok(live) => live,
+                Err(err) => {
+                    error!("Backfill terminated with error: {:#}", err);
+                    exit(1);
+                }
             }
         }
+        _ => builder.cursor(cursor),
+    };
+
+    let stream = builder.build()?;
+
+    let stream: Pin<Box<dyn Stream<Item = Result<BlockResponse, Error>> + Send>> =
+        match args.reorg_buffer {
+            None => Box::pin(stream),
+            Some(ReorgBufferMode::Final) => Box::pin(ReorgBuffer::new(stream)),
+            Some(ReorgBufferMode::Depth(depth)) => Box::pin(ReorgBuffer::with_depth(stream, depth)),
+        };
+
+    if let Err(err) = run_sink(stream, &mut sink).await {
+        error!("Stream terminated with error: {:#}", err);
+        exit(1);
     }
 
+    info!("Stream consumed");
     Ok(())
 }
 
-fn process_block_scoped_data(data: &BlockScopedData) -> Result<(), Error> {
+/// Refuses to stream when the endpoint does not serve the package's network, and
+/// checks the start block against the first block served by the endpoint.
+async fn check_endpoint(
+    endpoint: &SubstreamsEndpoint,
+    network: &str,
+    start_block: i64,
+    clamp_start_block: bool,
+) -> Result<i64, Error> {
+    let info = match endpoint.info().await {
+        Ok(info) => info,
+        Err(err)
+            if err
+                .downcast_ref::<tonic::Status>()
+                .map_or(false, |status| status.code() == tonic::Code::Unimplemented) =>
+        {
+            warn!("Endpoint does not implement EndpointInfo/Info, skipping endpoint checks");
+            return Ok(start_block);
+        }
+        Err(err) => return Err(err.context("query endpoint info")),
+    };
+
+    match network.is_empty() {
+        true => warn!("Package declares no network, skipping endpoint chain check"),
+        false => check_network(&info, network)?,
+    }
+
+    let checked = check_start_block(&info, start_block, clamp_start_block)
+        .context("use --clamp-start-block to start from the first block served instead")?;
+    if checked != start_block {
+        warn!(
+            "Start block #{} is below the first block served by the endpoint, starting from #{}",
+            start_block, checked
+        );
+    }
+
+    Ok(checked)
+}
+
+/// Checks the output module hash recorded for the cursor of `sink` before recording
+/// `output_module_hash`, for the sinks that cannot store it along with their cursor:
+/// it is kept next to the cursors, in `--cursor-dir`.
+fn record_module_hash(
+    args: &SinkArgs,
+    cursor_key: &CursorKey,
+    output_module_hash: &str,
+    sink: &dyn Sink,
+) -> Result<(), Error> {
+    let hashes = module_hash_store(args);
+
+    if sink.cursor()?.is_some() {
+        check_module_hash(
+            cursor_key,
+            hashes.load(cursor_key)?.as_deref(),
+            output_module_hash,
+        )?;
+    }
+
+    hashes.save(cursor_key, output_module_hash)
+}
+
+fn module_hash_store(args: &SinkArgs) -> FileCursorStore {
+    FileCursorStore::new(&args.cursor_dir).with_extension("module_hash")
+}
+
+fn checkpoint_store(args: &SinkArgs) -> FileCursorStore {
+    FileCursorStore::new(&args.cursor_dir).with_extension("checkpoint")
+}
+
+/// Backfill checkpoints are keyed by segment range, the checkpoints of every range
+/// backfilled for the cursor key are deleted.
+fn delete_checkpoints(args: &SinkArgs, cursor_key: &CursorKey) -> Result<(), Error> {
+    let store = checkpoint_store(args);
+    let prefix = store.path(&CursorKey::new(
+        &cursor_key.endpoint,
+        &cursor_key.package,
+        format!("{}@", cursor_key.output_module),
+    ));
+    let prefix = prefix
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .expect("cursor file names are encoded");
+
+    let entries = match fs::read_dir(&args.cursor_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).context(format!(
+                "read cursor directory '{}'",
+                args.cursor_dir.display()
+            ))
+        }
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        let is_checkpoint = path.extension().map_or(false, |ext| ext == "checkpoint")
+            && path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| name.starts_with(prefix));
+
+        if is_checkpoint {
+            fs::remove_file(&path)
+                .context(format!("delete checkpoint file '{}'", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn info(args: PackageArgs) -> Result<(), Error> {
+    let package = read_package(&args.package).await?;
+
+    if let Some(meta) = package.package_meta.first() {
+        println!("Name: {}", meta.name);
+        println!("Version: {}", meta.version);
+        if !meta.url.is_empty() {
+            println!("URL: {}", meta.url);
+        }
+        if !meta.doc.is_empty() {
+            println!("Doc: {}", meta.doc.trim());
+        }
+    }
+
+    if !package.network.is_empty() {
+        println!("Network: {}", package.network);
+    }
+
+    if !package.networks.is_empty() {
+        let mut networks: Vec<&String> = package.networks.keys().collect();
+        networks.sort();
+        println!(
+            "Networks: {}",
+            networks
+                .into_iter()
+                .map(|n| n.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    println!("Modules:");
+    for summary in module_summaries(&package) {
+        println!(
+            "  {} ({}) {}",
+            summary.name,
+            summary.kind,
+            summary.hash.as_deref().unwrap_or("<invalid module graph>")
+        );
+    }
+
+    Ok(())
+}
+
+async fn package_inspect(args: InspectArgs) -> Result<(), Error> {
+    let package = read_package(&args.package).await?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&package_json(&package))?);
+        return Ok(());
+    }
+
+    for summary in module_summaries(&package) {
+        println!("{}", summary);
+    }
+
+    Ok(())
+}
+
+async fn package_graph(args: GraphArgs) -> Result<(), Error> {
+    let package = read_package(&args.package).await?;
+    let modules = package
+        .modules
+        .as_ref()
+        .ok_or_else(|| format_err!("package has no modules"))?;
+
+    let graph = ModuleGraph::new(modules, args.module.as_deref())?;
+    match args.format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Mermaid => print!("{}", graph.to_mermaid()),
+    }
+
+    Ok(())
+}
+
+fn cursor_show(args: CursorArgs) -> Result<(), Error> {
+    let cursor_key = CursorKey::new(args.endpoint_url(), &args.package, &args.module);
+
+    // Opening the sink would create its storage or repair it, showing must not modify anything
+    let cursor = match (&args.sink.sqlite_path, &args.sink.output_file) {
+        (Some(path), _) => SqliteSink::read_cursor(path, &cursor_key)?,
+        (_, Some(path)) => FileSink::read_cursor(path)?,
+        _ => FileCursorStore::new(&args.sink.cursor_dir).load(&cursor_key)?,
+    };
+
+    match cursor {
+        Some(cursor) => println!("{}", cursor),
+        None => warn!(key = %cursor_key, "No cursor persisted"),
+    }
+
+    Ok(())
+}
+
+fn cursor_reset(args: CursorArgs) -> Result<(), Error> {
+    let cursor_key = CursorKey::new(args.endpoint_url(), &args.package, &args.module);
+
+    match (&args.sink.sqlite_path, &args.sink.output_file) {
+        (Some(path), _) => SqliteSink::open(path, cursor_key.clone(), args.sink.sqlite_batch_size)?
+            .delete(&cursor_key)?,
+        (_, Some(path)) => {
+            return Err(format_err!(
+            "the cursor of output file '{}' is read from its last line, remove the file instead",
+            path.display()
+        ))
+        }
+        _ => FileCursorStore::new(&args.sink.cursor_dir).delete(&cursor_key)?,
+    }
+    module_hash_store(&args.sink).delete(&cursor_key)?;
+    delete_checkpoints(&args.sink, &cursor_key)?;
+
+    info!(key = %cursor_key, "Cursor reset");
+    Ok(())
+}
+
+fn open_sink(
+    args: &SinkArgs,
+    cursor_key: &CursorKey,
+    output_module_hash: &str,
+) -> Result<Box<dyn Sink>, Error> {
+    // SQLite commits the module hash along with the cursor
+    if let Some(path) = &args.sqlite_path {
+        let sink = SqliteSink::open(path, cursor_key.clone(), args.sqlite_batch_size)?;
+        return Ok(Box::new(sink.with_module_hash(output_module_hash)?));
+    }
+
+    let sink: Box<dyn Sink> = match &args.output_file {
+        Some(path) => Box::new(FileSink::open(path)?),
+        None => Box::new(CursorSink::new(
+            FileCursorStore::new(&args.cursor_dir),
+            cursor_key.clone(),
+        )),
+    };
+
+    record_module_hash(args, cursor_key, output_module_hash, sink.as_ref())?;
+    Ok(sink)
+}
+
+/// Prints every block (with its decoded output) and undo signal received to standard
+/// output before handing them to the actual sink. Blocks are only accepted out of
+/// order when `out_of_order` is set, as they are then printed in that order too, or
+/// when the actual sink accepts them.
+struct PrintingSink<S: Sink> {
+    inner: S,
+    printer: Printer,
+    progress: ProgressReporter,
+    out_of_order: bool,
+}
+
+enum Printer {
+    Text(OutputDecoder),
+    JsonLines(JsonLinesSink<Stdout>),
+}
+
+impl<S: Sink> Sink for PrintingSink<S> {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        self.progress.clear();
+        match &mut self.printer {
+            Printer::Text(decoder) => process_block_scoped_data(data, decoder)?,
+            Printer::JsonLines(json) => json.handle_block(data)?,
+        }
+
+        self.inner.handle_block(data)
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        self.inner.undo(last_valid_block)
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        self.progress.clear();
+        match &mut self.printer {
+            Printer::Text(_) => process_block_undo_signal(signal)?,
+            Printer::JsonLines(json) => json.handle_undo(signal)?,
+        }
+
+        self.inner.handle_undo(signal)
+    }
+
+    fn handle_session(&mut self, session: &SessionInit) -> Result<(), Error> {
+        self.progress.tracker.handle_session(session);
+        self.inner.handle_session(session)
+    }
+
+    fn handle_progress(&mut self, progress: &Progress) -> Result<(), Error> {
+        self.progress.report(progress);
+        self.inner.handle_progress(progress)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.progress.clear();
+        self.inner.flush()
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        self.inner.cursor()
+    }
+
+    fn accepts_out_of_order(&self) -> bool {
+        self.out_of_order || self.inner.accepts_out_of_order()
+    }
+}
+
+/// Reports the server's parallel processing progress on standard error, redrawn in
+/// place on the `line` when set and logged otherwise. Progress events are throttled by
+/// the stream (`--progress-interval`), each one received is reported.
+struct ProgressReporter {
+    tracker: ProgressTracker,
+    line: Option<ProgressLine>,
+}
+
+impl ProgressReporter {
+    fn new(line: Option<ProgressLine>) -> Self {
+        ProgressReporter {
+            tracker: ProgressTracker::new(),
+            line,
+        }
+    }
+
+    fn report(&mut self, progress: &Progress) {
+        let summary = self.tracker.update(progress, Instant::now());
+
+        match &self.line {
+            Some(line) => line.draw(&summary),
+            None => info!("Progress: {}", summary),
+        }
+    }
+
+    /// Erases the progress line so that it does not get mixed with other output.
+    fn clear(&mut self) {
+        if let Some(line) = &self.line {
+            line.clear();
+        }
+    }
+}
+
+/// Progress line drawn in place on standard error. Also the writer of the log events,
+/// which erases the line first: events are logged from the stream's tasks (reconnects
+/// for example) while the line is drawn.
+#[derive(Clone, Default)]
+struct ProgressLine {
+    drawn: Arc<AtomicBool>,
+}
+
+impl ProgressLine {
+    fn draw(&self, summary: &ProgressSummary) {
+        eprint!("\r\x1b[K{}", summary);
+        self.drawn.store(true, Ordering::SeqCst);
+    }
+
+    fn clear(&self) {
+        if self.drawn.swap(false, Ordering::SeqCst) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for ProgressLine {
+    type Writer = Stderr;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clear();
+        stderr()
+    }
+}
+
+fn process_block_scoped_data(data: &BlockScopedData, decoder: &OutputDecoder) -> Result<(), Error> {
     let output = data.output.as_ref().unwrap().map_output.as_ref().unwrap();
 
-    // You can decode the actual Any type received using this code:
+    // The output is decoded at runtime using the Protobuf definitions shipped in the package,
+    // fields can be accessed dynamically with `value.get_field_by_name("...")`.
+    //
+    // You can also decode the actual Any type received using generated code:
     //
     //     let value = GeneratedStructName::decode(output.value.as_slice())?;
     //
     // Where GeneratedStructName is the Rust code generated for the Protobuf representing
     // your type, so you will need generate it using `substreams protogen` and import it from the
     // `src/pb` folder.
+    let value = decoder.decode(output);
 
     let clock = data.clock.as_ref().unwrap();
     let timestamp = clock.timestamp.as_ref().unwrap();
@@ -112,122 +645,141 @@ fn process_block_scoped_data(data: &BlockScopedData) -> Result<(), Error> {
         clock.number,
         output.type_url.replace("type.googleapis.com/", ""),
         output.value.len(),
-        date.signed_duration_since(chrono::offset::Utc::now())
+        -date
+            .signed_duration_since(chrono::offset::Utc::now())
             .num_seconds()
-            * -1
     );
 
+    match value {
+        Ok(value) => println!("{}", value),
+        Err(err) => println!("Unable to decode payload: {:#}", err),
+    }
+
     Ok(())
 }
 
-fn process_block_undo_signal(_undo_signal: &BlockUndoSignal) -> Result<(), anyhow::Error> {
+fn process_block_undo_signal(undo_signal: &BlockUndoSignal) -> Result<(), anyhow::Error> {
     // `BlockUndoSignal` must be treated as "delete every data that has been recorded after
     // block height specified by block in BlockUndoSignal". In the example above, this means
     // you must delete changes done by `Block #7b` and `Block #6b`. The exact details depends
     // on your own logic. If for example all your added record contain a block number, a
     // simple way is to do `delete all records where block_num > 5` which is the block num
     // received in the `BlockUndoSignal` (this is true for append only records, so when only `INSERT` are allowed).
-    unimplemented!("you must implement some kind of block undo handling, or request only final blocks (tweak substreams_stream.rs)")
-}
-
-fn persist_cursor(_cursor: String) -> Result<(), anyhow::Error> {
-    // FIXME: Handling of the cursor is missing here. It should be saved each time
-    // a full block has been correctly processed/persisted. The saving location
-    // is your responsibility.
     //
-    // By making it persistent, we ensure that if we crash, on startup we are
-    // going to read it back from database and start back our SubstreamsStream
-    // with it ensuring we are continuously streaming without ever losing a single
-    // element.
-    Ok(())
-}
-
-fn load_persisted_cursor() -> Result<Option<String>, anyhow::Error> {
-    // FIXME: Handling of the cursor is missing here. It should be loaded from
-    // somewhere (local file, database, cloud storage) and then `SubstreamStream` will
-    // be able correctly resume from the right block.
-    Ok(None)
-}
-
-fn read_block_range(pkg: &Package, module_name: &str) -> Result<(i64, u64), anyhow::Error> {
-    let module = pkg
-        .modules
+    // Each `Sink` implements this through its `undo` method.
+    let last_valid_block = undo_signal
+        .last_valid_block
         .as_ref()
-        .unwrap()
-        .modules
-        .iter()
-        .find(|m| m.name == module_name)
-        .ok_or_else(|| format_err!("module '{}' not found in package", module_name))?;
-
-    let mut input: String = "".to_string();
-    if let Some(range) = env::args().nth(4) {
-        input = range;
-    };
-
-    let (prefix, suffix) = match input.split_once(":") {
-        Some((prefix, suffix)) => (prefix.to_string(), suffix.to_string()),
-        None => ("".to_string(), input),
-    };
-
-    let start: i64 = match prefix.as_str() {
-        "" => module.initial_block as i64,
-        x if x.starts_with("+") => {
-            let block_count = x
-                .trim_start_matches("+")
-                .parse::<u64>()
-                .context("argument <stop> is not a valid integer")?;
+        .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
 
-            (module.initial_block + block_count) as i64
-        }
-        x => x
-            .parse::<i64>()
-            .context("argument <start> is not a valid integer")?,
-    };
-
-    let stop: u64 = match suffix.as_str() {
-        "" => 0,
-        "-" => 0,
-        x if x.starts_with("+") => {
-            let block_count = x
-                .trim_start_matches("+")
-                .parse::<u64>()
-                .context("argument <stop> is not a valid integer")?;
-
-            start as u64 + block_count
-        }
-        x => x
-            .parse::<u64>()
-            .context("argument <stop> is not a valid integer")?,
-    };
+    println!(
+        "Undo signal received, reverting everything above block #{} ({})",
+        last_valid_block.number, last_valid_block.id
+    );
 
-    return Ok((start, stop));
+    Ok(())
 }
 
-async fn read_package(input: &str) -> Result<Package, anyhow::Error> {
+async fn read_package(args: &PackageSourceArgs) -> Result<Package, anyhow::Error> {
+    let input = args.package.as_str();
     let mut mutable_input = input.to_string();
 
+    // Registry versions are immutable, except `latest`
+    let mut pinned = false;
     let val = parse_standard_package_and_version(input);
     if val.is_ok() {
         let package_and_version = val.unwrap();
+        pinned = package_and_version.1 != "latest";
         mutable_input = format!(
             "{}/v1/packages/{}/{}",
             REGISTRY_URL, package_and_version.0, package_and_version.1
         );
     }
 
-    if mutable_input.starts_with("http") {
-        return read_http_package(&mutable_input).await;
+    let content = match mutable_input.starts_with("http") {
+        true => read_http_package(&mutable_input, pinned, args).await?,
+        // Assume it's a local file
+        false => std::fs::read(&mutable_input)
+            .context(format_err!("read package from file '{}'", mutable_input))?,
+    };
+
+    if let Some(expected) = &args.expect_sha256 {
+        let actual = sha256_hex(&content);
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(format_err!(
+                "package '{}' SHA-256 is {} but {} was expected",
+                input,
+                actual,
+                expected
+            ));
+        }
     }
 
-    // Assume it's a local file
-    let content = std::fs::read(&mutable_input)
-        .context(format_err!("read package from file '{}'", mutable_input))?;
     Package::decode(content.as_ref()).context("decode command")
 }
-async fn read_http_package(input: &str) -> Result<Package, anyhow::Error> {
-    let body = reqwest::get(input).await?.bytes().await?;
 
-    Package::decode(body).context("decode command")
+async fn read_http_package(
+    input: &str,
+    pinned: bool,
+    args: &PackageSourceArgs,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let cache = args
+        .spkg_cache_dir
+        .clone()
+        .or_else(SpkgCache::default_directory)
+        .map(SpkgCache::new);
+
+    let Some(cache) = cache else {
+        if args.offline {
+            return Err(format_err!(
+                "no package cache directory (HOME is not set), use --spkg-cache-dir"
+            ));
+        }
+
+        return Ok(reqwest::get(input).await?.bytes().await?.to_vec());
+    };
+
+    let max_age = match pinned || args.offline {
+        true => None,
+        false => Some(Duration::from_secs(args.spkg_cache_ttl)),
+    };
+    if let Some(content) = cache.get(input, max_age)? {
+        return Ok(content);
+    }
+
+    if args.offline {
+        return Err(format_err!(
+            "package '{}' is not cached and --offline is set",
+            input
+        ));
+    }
+
+    let fetched = match reqwest::get(input).await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response.bytes().await.map(|body| body.to_vec()),
+        Err(err) => Err(err),
+    };
+
+    match fetched {
+        Ok(content) => {
+            // Only valid packages are cached, an error page would otherwise be served until expiry
+            Package::decode(content.as_slice()).context("decode command")?;
+            if let Err(err) = cache.put(input, &content) {
+                warn!("Unable to cache package '{}': {:#}", input, err);
+            }
+
+            Ok(content)
+        }
+        Err(err) => match cache.get(input, None)? {
+            Some(content) => {
+                warn!(
+                    "Unable to refresh package '{}', using the cached one: {}",
+                    input, err
+                );
+                Ok(content)
+            }
+            None => Err(err.into()),
+        },
+    }
 }
 
 fn parse_standard_package_and_version(input: &str) -> Result<(String, String), Error> {
@@ -0,0 +1,455 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::{Context, Error};
+use chrono::Utc;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal};
+use crate::progress::Progress;
+
+/// Prometheus metrics of a stream, recorded by `SubstreamsStream` when set through
+/// the builder's `metrics` option and exported by a [MetricsServer].
+///
+/// Throughput is left to the scraper, `rate(substreams_blocks_total[1m])`, so that it
+/// drops as soon as the stream stalls.
+///
+/// The `ModulesProgress` counters are totals computed by the server for the current
+/// session, they are exported as gauges since they start over on reconnection.
+pub struct Metrics {
+    registry: Registry,
+    head_block_number: IntGauge,
+    head_block_drift_seconds: Gauge,
+    blocks: IntCounter,
+    undos: IntCounter,
+    undo_depth: Histogram,
+    reconnects: IntCounterVec,
+    backoff_delay_seconds: Gauge,
+    received_bytes: IntCounter,
+    processed_bytes_read: IntGauge,
+    processed_bytes_written: IntGauge,
+    module_processed_blocks: IntGaugeVec,
+    module_processing_time_seconds: GaugeVec,
+    module_store_operation_time_seconds: GaugeVec,
+    module_store_reads: IntGaugeVec,
+    module_store_writes: IntGaugeVec,
+    module_store_deleteprefixes: IntGaugeVec,
+    module_store_size_bytes: IntGaugeVec,
+    module_store_merging_time_seconds: GaugeVec,
+    module_external_calls: IntGaugeVec,
+    module_external_call_time_seconds: GaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, Error> {
+        let registry = Registry::new();
+
+        macro_rules! register {
+            ($metric:expr) => {{
+                let metric = $metric?;
+                registry.register(Box::new(metric.clone()))?;
+                metric
+            }};
+        }
+
+        Ok(Metrics {
+            head_block_number: register!(IntGauge::new(
+                "substreams_head_block_number",
+                "Number of the last block received"
+            )),
+            head_block_drift_seconds: register!(Gauge::new(
+                "substreams_head_block_drift_seconds",
+                "Seconds between the last block's timestamp and the time it was received"
+            )),
+            blocks: register!(IntCounter::new(
+                "substreams_blocks_total",
+                "Number of blocks received"
+            )),
+            undos: register!(IntCounter::new(
+                "substreams_undo_total",
+                "Number of undo signals received"
+            )),
+            undo_depth: register!(Histogram::with_opts(
+                HistogramOpts::new(
+                    "substreams_undo_depth_blocks",
+                    "Number of blocks reverted by undo signals"
+                )
+                .buckets(vec![1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0, 100.0])
+            )),
+            reconnects: register!(IntCounterVec::new(
+                Opts::new(
+                    "substreams_reconnects_total",
+                    "Number of reconnections, by gRPC status code of the error that caused them"
+                ),
+                &["code"]
+            )),
+            backoff_delay_seconds: register!(Gauge::new(
+                "substreams_backoff_delay_seconds",
+                "Delay before the next reconnection attempt, 0 while connected"
+            )),
+            received_bytes: register!(IntCounter::new(
+                "substreams_received_bytes_total",
+                "Size of the (decompressed) messages received from the endpoint"
+            )),
+            processed_bytes_read: register!(IntGauge::new(
+                "substreams_processed_bytes_read",
+                "Bytes read by the server for the current session"
+            )),
+            processed_bytes_written: register!(IntGauge::new(
+                "substreams_processed_bytes_written",
+                "Bytes written by the server for the current session"
+            )),
+            module_processed_blocks: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_processed_blocks",
+                    "Blocks processed by the module"
+                ),
+                &["module"]
+            )),
+            module_processing_time_seconds: register!(GaugeVec::new(
+                Opts::new(
+                    "substreams_module_processing_time_seconds",
+                    "Time spent processing blocks in the module"
+                ),
+                &["module"]
+            )),
+            module_store_operation_time_seconds: register!(GaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_operation_time_seconds",
+                    "Time spent in store operations of the module"
+                ),
+                &["module"]
+            )),
+            module_store_reads: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_reads",
+                    "Store reads done by the module"
+                ),
+                &["module"]
+            )),
+            module_store_writes: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_writes",
+                    "Store writes done by the module"
+                ),
+                &["module"]
+            )),
+            module_store_deleteprefixes: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_deleteprefixes",
+                    "Store delete prefix operations done by the module"
+                ),
+                &["module"]
+            )),
+            module_store_size_bytes: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_size_bytes",
+                    "Size of the module's store"
+                ),
+                &["module"]
+            )),
+            module_store_merging_time_seconds: register!(GaugeVec::new(
+                Opts::new(
+                    "substreams_module_store_merging_time_seconds",
+                    "Time spent merging the module's store"
+                ),
+                &["module"]
+            )),
+            module_external_calls: register!(IntGaugeVec::new(
+                Opts::new(
+                    "substreams_module_external_calls",
+                    "External calls done by the module"
+                ),
+                &["module", "call"]
+            )),
+            module_external_call_time_seconds: register!(GaugeVec::new(
+                Opts::new(
+                    "substreams_module_external_call_time_seconds",
+                    "Time spent in external calls of the module"
+                ),
+                &["module", "call"]
+            )),
+            registry,
+        })
+    }
+
+    pub fn record_block(&self, data: &BlockScopedData) {
+        self.blocks.inc();
+
+        if let Some(clock) = &data.clock {
+            self.head_block_number.set(clock.number as i64);
+
+            if let Some(timestamp) = &clock.timestamp {
+                let received_at = Utc::now();
+                let drift = received_at.timestamp() as f64 - timestamp.seconds as f64
+                    + (received_at.timestamp_subsec_nanos() as f64 - timestamp.nanos as f64) / 1e9;
+                self.head_block_drift_seconds.set(drift);
+            }
+        }
+    }
+
+    pub fn record_undo(&self, signal: &BlockUndoSignal) {
+        self.undos.inc();
+
+        if let Some(last_valid_block) = &signal.last_valid_block {
+            let head = self.head_block_number.get() as u64;
+            self.undo_depth
+                .observe(head.saturating_sub(last_valid_block.number) as f64);
+            self.head_block_number.set(last_valid_block.number as i64);
+        }
+    }
+
+    pub fn record_progress(&self, progress: &Progress) {
+        self.processed_bytes_read
+            .set(progress.processed_bytes.total_bytes_read as i64);
+        self.processed_bytes_written
+            .set(progress.processed_bytes.total_bytes_written as i64);
+
+        for stats in &progress.modules_stats {
+            let module = [stats.name.as_str()];
+            self.module_processed_blocks
+                .with_label_values(&module)
+                .set(stats.total_processed_block_count as i64);
+            self.module_processing_time_seconds
+                .with_label_values(&module)
+                .set(stats.total_processing_time_ms as f64 / 1000.0);
+            self.module_store_operation_time_seconds
+                .with_label_values(&module)
+                .set(stats.total_store_operation_time_ms as f64 / 1000.0);
+            self.module_store_reads
+                .with_label_values(&module)
+                .set(stats.total_store_read_count as i64);
+            self.module_store_writes
+                .with_label_values(&module)
+                .set(stats.total_store_write_count as i64);
+            self.module_store_deleteprefixes
+                .with_label_values(&module)
+                .set(stats.total_store_deleteprefix_count as i64);
+            self.module_store_size_bytes
+                .with_label_values(&module)
+                .set(stats.store_size_bytes as i64);
+            self.module_store_merging_time_seconds
+                .with_label_values(&module)
+                .set(stats.total_store_merging_time_ms as f64 / 1000.0);
+
+            for call in &stats.external_call_metrics {
+                let labels = [stats.name.as_str(), call.name.as_str()];
+                self.module_external_calls
+                    .with_label_values(&labels)
+                    .set(call.count as i64);
+                self.module_external_call_time_seconds
+                    .with_label_values(&labels)
+                    .set(call.time_ms as f64 / 1000.0);
+            }
+        }
+    }
+
+    /// Records a reconnection caused by an error with status `code`, after `delay`.
+    pub fn record_reconnect(&self, code: tonic::Code, delay: Duration) {
+        self.reconnects
+            .with_label_values(&[&format!("{:?}", code)])
+            .inc();
+        self.backoff_delay_seconds.set(delay.as_secs_f64());
+    }
+
+    /// Resets the backoff delay once messages are received again.
+    pub fn record_connected(&self) {
+        self.backoff_delay_seconds.set(0.0);
+    }
+
+    pub fn record_received_bytes(&self, bytes: usize) {
+        self.received_bytes.inc_by(bytes as u64);
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn encode(&self) -> Result<String, Error> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Serves [Metrics] over HTTP at `/metrics`, for Prometheus to scrape.
+pub struct MetricsServer {
+    listener: TcpListener,
+}
+
+impl MetricsServer {
+    pub async fn bind(address: SocketAddr) -> Result<Self, Error> {
+        let listener = TcpListener::bind(address)
+            .await
+            .context(format!("bind metrics server to {}", address))?;
+
+        Ok(MetricsServer { listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Accepts connections until the task is dropped.
+    pub async fn serve(self, metrics: Arc<Metrics>) -> Result<(), Error> {
+        loop {
+            let (connection, peer) = self.listener.accept().await?;
+            let metrics = metrics.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = respond(connection, &metrics).await {
+                    tracing::warn!(%peer, "Unable to serve metrics: {:#}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Minimal HTTP/1.1 handling, only the request line matters and the connection is
+/// closed after each response.
+async fn respond(mut connection: TcpStream, metrics: &Metrics) -> Result<(), Error> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = connection.read(&mut buffer).await?;
+        if read == 0 || request.len() > 16 * 1024 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, content_type, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics.encode()?)
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    connection.write_all(response.as_bytes()).await?;
+    connection.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::sf::substreams::{
+        rpc::v2::{ExternalCallMetric, ModuleStats, ModulesProgress, ProcessedBytes},
+        v1::{BlockRef, Clock},
+    };
+
+    fn block(number: u64) -> BlockScopedData {
+        BlockScopedData {
+            clock: Some(Clock {
+                number,
+                timestamp: Some(prost_types::Timestamp {
+                    seconds: Utc::now().timestamp() - 12,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_to_local_scrape() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        metrics.record_block(&block(100));
+        metrics.record_block(&block(101));
+        metrics.record_block(&block(102));
+        metrics.record_undo(&BlockUndoSignal {
+            last_valid_block: Some(BlockRef {
+                number: 100,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        metrics.record_reconnect(tonic::Code::Unavailable, Duration::from_millis(500));
+        metrics.record_received_bytes(2048);
+        metrics.record_progress(&Progress::from(ModulesProgress {
+            modules_stats: vec![ModuleStats {
+                name: "map_swaps".to_string(),
+                total_processing_time_ms: 1500,
+                total_store_read_count: 42,
+                external_call_metrics: vec![ExternalCallMetric {
+                    name: "eth_call".to_string(),
+                    count: 7,
+                    time_ms: 250,
+                }],
+                ..Default::default()
+            }],
+            processed_bytes: Some(ProcessedBytes {
+                total_bytes_read: 1000,
+                total_bytes_written: 10,
+            }),
+            ..Default::default()
+        }));
+
+        let server = MetricsServer::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let address = server.local_addr().unwrap();
+        tokio::spawn(server.serve(metrics));
+
+        let response = reqwest::get(format!("http://{}/metrics", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body = response.text().await.unwrap();
+
+        for line in [
+            "substreams_head_block_number 100",
+            "substreams_blocks_total 3",
+            "substreams_undo_total 1",
+            "substreams_undo_depth_blocks_bucket{le=\"2\"} 1",
+            "substreams_reconnects_total{code=\"Unavailable\"} 1",
+            "substreams_backoff_delay_seconds 0.5",
+            "substreams_received_bytes_total 2048",
+            "substreams_processed_bytes_read 1000",
+            "substreams_module_processing_time_seconds{module=\"map_swaps\"} 1.5",
+            "substreams_module_store_reads{module=\"map_swaps\"} 42",
+            "substreams_module_external_calls{call=\"eth_call\",module=\"map_swaps\"} 7",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "missing '{}' in:\n{}",
+                line,
+                body
+            );
+        }
+
+        let drift: f64 = body
+            .lines()
+            .find_map(|l| l.strip_prefix("substreams_head_block_drift_seconds "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((11.0..20.0).contains(&drift));
+
+        let response = reqwest::get(format!("http://{}/other", address))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+    }
+}
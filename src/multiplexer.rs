@@ -0,0 +1,223 @@
+use std::{pin::Pin, sync::Arc};
+
+use anyhow::{format_err, Error};
+use futures03::Stream;
+use tokio::sync::watch;
+use tracing::{error, info_span, Instrument};
+
+use crate::sink::{run_sink, Sink};
+use crate::substreams_stream::{BlockResponse, SubstreamsStreamBuilder};
+
+type BoxStream = Pin<Box<dyn Stream<Item = Result<BlockResponse, Error>> + Send>>;
+
+/// Streams several output modules concurrently, each one into its own [Sink] and
+/// resuming from that sink's cursor.
+///
+/// Every module's stream is built from the same builder template, so they all share
+/// its `SubstreamsEndpoint` and thus a single gRPC channel. Streams run in their own
+/// task: a stream failing, or its sink, does not stop the other ones, its error is
+/// reported in the result of [Multiplexer::run] once every stream is done.
+pub struct Multiplexer {
+    template: SubstreamsStreamBuilder,
+    streams: Vec<(String, BoxStream, Box<dyn Sink + Send>)>,
+    shutdown: Arc<watch::Sender<bool>>,
+}
+
+/// Stops every stream of a [Multiplexer], see [Multiplexer::shutdown_handle].
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+impl Multiplexer {
+    /// `template` holds the configuration shared by every stream (endpoint, modules,
+    /// block range, ...), the output module and the cursor are set per stream.
+    pub fn new(template: SubstreamsStreamBuilder) -> Self {
+        Multiplexer {
+            template,
+            streams: vec![],
+            shutdown: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    /// Streams `output_module` into `sink`, starting from the sink's cursor.
+    pub fn add_module<K>(&mut self, output_module: &str, sink: K) -> Result<(), Error>
+    where
+        K: Sink + Send + 'static,
+    {
+        let stream = self
+            .template
+            .clone()
+            .output_module(output_module)
+            .cursor(sink.cursor()?)
+            .build()?;
+
+        self.add_stream(output_module, stream, sink);
+        Ok(())
+    }
+
+    /// Drives an arbitrary `stream` into `sink`, reported under `name`.
+    pub fn add_stream<S, K>(&mut self, name: &str, stream: S, sink: K)
+    where
+        S: Stream<Item = Result<BlockResponse, Error>> + Send + 'static,
+        K: Sink + Send + 'static,
+    {
+        self.streams
+            .push((name.to_string(), Box::pin(stream), Box::new(sink)));
+    }
+
+    /// Once shut down, every stream stops after the block it is currently handling
+    /// and its sink is flushed. Streams not started yet return right away.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            sender: self.shutdown.clone(),
+        }
+    }
+
+    /// Runs every stream to completion, or until shut down, and returns the outcome of
+    /// each one in the order they were added.
+    pub async fn run(self) -> Vec<(String, Result<(), Error>)> {
+        let tasks: Vec<_> = self
+            .streams
+            .into_iter()
+            .map(|(name, stream, mut sink)| {
+                let mut shutdown = self.shutdown.subscribe();
+                let span = info_span!("stream", module = %name);
+                let task = tokio::spawn(
+                    async move {
+                        if *shutdown.borrow_and_update() {
+                            return sink.flush();
+                        }
+
+                        tokio::select! {
+                            result = run_sink(stream, &mut sink) => result,
+                            _ = shutdown.changed() => sink.flush(),
+                        }
+                    }
+                    .instrument(span),
+                );
+
+                (name, task)
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(tasks.len());
+        for (name, task) in tasks {
+            let result = match task.await {
+                Ok(result) => result,
+                Err(e) => Err(format_err!("stream task panicked: {}", e)),
+            };
+
+            if let Err(e) = &result {
+                error!(module = %name, "Stream failed: {:#}", e);
+            }
+
+            results.push((name, result));
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures03::{stream, StreamExt};
+
+    use super::*;
+    use crate::pb::sf::substreams::{rpc::v2::BlockScopedData, v1::BlockRef};
+    use crate::sink::{tests::block, MemorySink};
+
+    #[derive(Clone, Default)]
+    struct SharedSink {
+        inner: Arc<Mutex<MemorySink>>,
+        flushed: Arc<Mutex<bool>>,
+    }
+
+    impl Sink for SharedSink {
+        fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+            self.inner.lock().unwrap().handle_block(data)
+        }
+
+        fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+            self.inner.lock().unwrap().undo(last_valid_block)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            *self.flushed.lock().unwrap() = true;
+            Ok(())
+        }
+
+        fn cursor(&self) -> Result<Option<String>, Error> {
+            self.inner.lock().unwrap().cursor()
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_errors_are_isolated() {
+        let transfers = SharedSink::default();
+        let pools = SharedSink::default();
+
+        let mut multiplexer = Multiplexer::new(SubstreamsStreamBuilder::default());
+        multiplexer.add_stream(
+            "map_transfers",
+            stream::iter(vec![block(1, "a", 0), block(2, "b", 0)].into_iter().map(Ok)),
+            transfers.clone(),
+        );
+        multiplexer.add_stream(
+            "map_pools",
+            stream::iter(vec![Ok(block(1, "a", 0)), Err(format_err!("boom"))]),
+            pools.clone(),
+        );
+
+        let results = multiplexer.run().await;
+
+        assert_eq!(results[0].0, "map_transfers");
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].0, "map_pools");
+        assert_eq!(results[1].1.as_ref().unwrap_err().to_string(), "boom");
+
+        assert_eq!(transfers.inner.lock().unwrap().blocks().count(), 2);
+        assert_eq!(
+            pools.cursor().unwrap().as_deref(),
+            Some("cursor-a"),
+            "blocks received before the error are kept"
+        );
+        assert!(*pools.flushed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_every_stream() {
+        let live = SharedSink::default();
+
+        let mut multiplexer = Multiplexer::new(SubstreamsStreamBuilder::default());
+        multiplexer.add_stream(
+            "map_live",
+            stream::iter(vec![Ok(block(1, "a", 0))]).chain(stream::pending()),
+            live.clone(),
+        );
+        multiplexer.add_stream(
+            "map_done",
+            stream::iter(vec![Ok(block(1, "a", 0))]),
+            SharedSink::default(),
+        );
+
+        let handle = multiplexer.shutdown_handle();
+        let run = tokio::spawn(multiplexer.run());
+        while live.cursor().unwrap().is_none() {
+            tokio::task::yield_now().await;
+        }
+        handle.shutdown();
+
+        let results = run.await.unwrap();
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert!(*live.flushed.lock().unwrap());
+    }
+}
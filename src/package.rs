@@ -0,0 +1,668 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+};
+
+use anyhow::{format_err, Error};
+use sha1::{Digest, Sha1};
+
+use crate::pb::sf::substreams::v1::{
+    module::{
+        self,
+        block_filter::Query,
+        input::{Input, Params},
+    },
+    Module, Modules, Package,
+};
+
+pub fn find_module<'a>(modules: &'a Modules, name: &str) -> Result<&'a Module, Error> {
+    modules
+        .modules
+        .iter()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format_err!("module '{}' not found in package", name))
+}
+
+pub fn find_module_mut<'a>(modules: &'a mut Modules, name: &str) -> Result<&'a mut Module, Error> {
+    modules
+        .modules
+        .iter_mut()
+        .find(|m| m.name == name)
+        .ok_or_else(|| format_err!("module '{}' not found in package", name))
+}
+
+/// Rewrites the `Params` input of each named module with the given value, so that
+/// a package can be reused with different params (a contract address for example)
+/// without being rebuilt.
+///
+/// Fails when a module does not exist or does not take a `Params` input, in which
+/// case `modules` is left untouched.
+pub fn apply_params(modules: &mut Modules, params: &[(String, String)]) -> Result<(), Error> {
+    for (name, _) in params {
+        let module = find_module(modules, name)?;
+        if params_input(module).is_none() {
+            return Err(format_err!(
+                "module '{}' does not take a params input",
+                name
+            ));
+        }
+    }
+
+    for (name, value) in params {
+        let module = find_module_mut(modules, name)?;
+        if let Some(params) = params_input_mut(module) {
+            params.value = value.clone();
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies the initial blocks and params declared in `Package.networks` for the
+/// selected network (`Package.network` when `network` is `None`) to the module
+/// graph, and records it as the package's network.
+///
+/// Packages declaring no networks are only accepted for their own network, if any.
+pub fn apply_network(package: &mut Package, network: Option<&str>) -> Result<(), Error> {
+    let selected = network.unwrap_or(&package.network).to_string();
+
+    if package.networks.is_empty() {
+        if !package.network.is_empty() && selected != package.network {
+            return Err(format_err!(
+                "network '{}' is not declared in package, it only supports '{}'",
+                selected,
+                package.network
+            ));
+        }
+
+        package.network = selected;
+        return Ok(());
+    }
+
+    let mut declared: Vec<&String> = package.networks.keys().collect();
+    declared.sort();
+    let declared = declared
+        .into_iter()
+        .map(|n| n.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if selected.is_empty() {
+        return Err(format_err!(
+            "package has no default network, select one of: {}",
+            declared
+        ));
+    }
+
+    let network_params = package.networks.get(&selected).ok_or_else(|| {
+        format_err!(
+            "network '{}' is not declared in package, declared networks: {}",
+            selected,
+            declared
+        )
+    })?;
+
+    let modules = package
+        .modules
+        .as_mut()
+        .ok_or_else(|| format_err!("package has no modules"))?;
+
+    for (name, initial_block) in &network_params.initial_blocks {
+        find_module_mut(modules, name)
+            .map_err(|e| e.context(format!("apply network '{}' initial blocks", selected)))?
+            .initial_block = *initial_block;
+    }
+
+    let mut params: Vec<(String, String)> = network_params
+        .params
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    params.sort();
+    apply_params(modules, &params)
+        .map_err(|e| e.context(format!("apply network '{}' params", selected)))?;
+
+    package.network = selected;
+    Ok(())
+}
+
+/// Names of the modules `module` directly depends on, through its map and store inputs.
+pub fn dependencies(module: &Module) -> impl Iterator<Item = &str> {
+    module.inputs.iter().filter_map(|input| match &input.input {
+        Some(Input::Map(map)) => Some(map.module_name.as_str()),
+        Some(Input::Store(store)) => Some(store.module_name.as_str()),
+        _ => None,
+    })
+}
+
+/// Names of every module `name` transitively depends on, sorted. Fails when a module
+/// depends on an unknown module or when the dependencies form a cycle.
+pub fn ancestors(modules: &Modules, name: &str) -> Result<BTreeSet<String>, Error> {
+    let mut ancestors = BTreeSet::new();
+    collect_ancestors(modules, name, &mut vec![name], &mut ancestors)?;
+
+    Ok(ancestors)
+}
+
+fn collect_ancestors<'a>(
+    modules: &'a Modules,
+    name: &str,
+    path: &mut Vec<&'a str>,
+    ancestors: &mut BTreeSet<String>,
+) -> Result<(), Error> {
+    let module = find_module(modules, name)?;
+
+    for dependency in dependencies(module) {
+        if path.contains(&dependency) {
+            path.push(dependency);
+            return Err(format_err!(
+                "module graph has a cycle: {}",
+                path.join(" -> ")
+            ));
+        }
+
+        if find_module(modules, dependency).is_err() {
+            return Err(format_err!(
+                "module '{}' depends on unknown module '{}'",
+                name,
+                dependency
+            ));
+        }
+
+        if ancestors.insert(dependency.to_string()) {
+            path.push(dependency);
+            collect_ancestors(modules, dependency, path, ancestors)?;
+            path.pop();
+        }
+    }
+
+    Ok(())
+}
+
+/// Keeps only the modules needed to compute `output_modules` (themselves, the
+/// modules they transitively depend on and the index modules of their block
+/// filters) along with the binaries they reference, `binary_index` being rewritten
+/// accordingly. Module and binary order is preserved.
+pub fn prune_modules(modules: &Modules, output_modules: &[&str]) -> Result<Modules, Error> {
+    let mut needed = BTreeSet::new();
+    let mut pending: Vec<String> = output_modules.iter().map(|m| m.to_string()).collect();
+
+    while let Some(name) = pending.pop() {
+        if !needed.insert(name.clone()) {
+            continue;
+        }
+
+        let module = find_module(modules, &name)?;
+        pending.extend(ancestors(modules, &name)?);
+        if let Some(filter) = &module.block_filter {
+            pending.push(filter.module.clone());
+        }
+    }
+
+    let kept: Vec<&Module> = modules
+        .modules
+        .iter()
+        .filter(|m| needed.contains(&m.name))
+        .collect();
+
+    let mut binary_indexes: Vec<u32> = kept.iter().map(|m| m.binary_index).collect();
+    binary_indexes.sort_unstable();
+    binary_indexes.dedup();
+
+    let binaries = binary_indexes
+        .iter()
+        .map(|index| {
+            modules
+                .binaries
+                .get(*index as usize)
+                .cloned()
+                .ok_or_else(|| format_err!("module graph references unknown binary #{}", index))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let modules = kept
+        .into_iter()
+        .map(|module| {
+            let mut module = module.clone();
+            module.binary_index = binary_indexes
+                .binary_search(&module.binary_index)
+                .expect("binary index was collected above")
+                as u32;
+            module
+        })
+        .collect();
+
+    Ok(Modules { modules, binaries })
+}
+
+/// Hash identifying what the module computes: its kind, code, inputs (params value
+/// included) and initial block, as well as the hashes of all the modules it depends
+/// on. Port of the module hashing of the reference Substreams implementation, the
+/// value is the one the server sends back as `PackageInfo.output_module_hash`.
+///
+/// A cursor is only valid for the module hash it was received for.
+pub fn module_hash(modules: &Modules, name: &str) -> Result<String, Error> {
+    let hash = hash_module(modules, name, &mut HashMap::new())?;
+
+    Ok(hash
+        .iter()
+        .fold(String::with_capacity(40), |mut output, byte| {
+            write!(output, "{:02x}", byte).expect("writing to a String never fails");
+            output
+        }))
+}
+
+fn hash_module(
+    modules: &Modules,
+    name: &str,
+    cache: &mut HashMap<String, Vec<u8>>,
+) -> Result<Vec<u8>, Error> {
+    if let Some(hash) = cache.get(name) {
+        return Ok(hash.clone());
+    }
+
+    let module = find_module(modules, name)?;
+    let mut buf: Vec<u8> = Vec::new();
+
+    buf.extend_from_slice(b"initial_block");
+    buf.extend_from_slice(module.initial_block.to_string().as_bytes());
+
+    buf.extend_from_slice(b"kind");
+    buf.extend_from_slice(match module.kind {
+        Some(module::Kind::KindMap(_)) => b"map".as_slice(),
+        Some(module::Kind::KindStore(_)) => b"store".as_slice(),
+        Some(module::Kind::KindBlockIndex(_)) => b"block_index".as_slice(),
+        None => return Err(format_err!("module '{}' has no kind", name)),
+    });
+
+    let binary = modules
+        .binaries
+        .get(module.binary_index as usize)
+        .ok_or_else(|| {
+            format_err!(
+                "module '{}' references unknown binary #{}",
+                name,
+                module.binary_index
+            )
+        })?;
+    buf.extend_from_slice(b"binary");
+    buf.extend_from_slice(binary.r#type.as_bytes());
+    buf.extend_from_slice(&binary.content);
+
+    buf.extend_from_slice(b"inputs");
+    for input in module
+        .inputs
+        .iter()
+        .filter_map(|input| input.input.as_ref())
+    {
+        match input {
+            Input::Source(source) => {
+                buf.extend_from_slice(b"source");
+                buf.extend_from_slice(source.r#type.as_bytes());
+            }
+            Input::Map(map) => {
+                buf.extend_from_slice(b"map");
+                buf.extend_from_slice(map.module_name.as_bytes());
+            }
+            Input::Store(store) => {
+                buf.extend_from_slice(b"store");
+                buf.extend_from_slice(store.module_name.as_bytes());
+            }
+            Input::Params(params) => {
+                buf.extend_from_slice(b"params");
+                buf.extend_from_slice(params.value.as_bytes());
+            }
+        }
+    }
+
+    // Ancestors are written ordered by hash, not by name
+    let mut ancestor_hashes = ancestors(modules, name)?
+        .iter()
+        .map(|ancestor| hash_module(modules, ancestor, cache))
+        .collect::<Result<Vec<_>, _>>()?;
+    ancestor_hashes.sort();
+
+    buf.extend_from_slice(b"ancestors");
+    for hash in ancestor_hashes {
+        buf.extend_from_slice(&hash);
+    }
+
+    buf.extend_from_slice(b"entrypoint");
+    buf.extend_from_slice(module.binary_entrypoint.as_bytes());
+
+    if let Some(filter) = &module.block_filter {
+        buf.extend_from_slice(b"block_filter");
+        buf.extend_from_slice(filter.module.as_bytes());
+        match &filter.query {
+            Some(Query::QueryString(query)) => buf.extend_from_slice(query.as_bytes()),
+            Some(Query::QueryFromParams(_)) => buf.extend_from_slice(b"params"),
+            None => {}
+        }
+    }
+
+    let hash = Sha1::digest(&buf).to_vec();
+    cache.insert(name.to_string(), hash.clone());
+
+    Ok(hash)
+}
+
+fn params_input(module: &Module) -> Option<&Params> {
+    module.inputs.iter().find_map(|input| match &input.input {
+        Some(Input::Params(params)) => Some(params),
+        _ => None,
+    })
+}
+
+fn params_input_mut(module: &mut Module) -> Option<&mut Params> {
+    module
+        .inputs
+        .iter_mut()
+        .find_map(|input| match &mut input.input {
+            Some(Input::Params(params)) => Some(params),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::pb::sf::substreams::v1::{
+        module::input::{store, Map, Source, Store},
+        Binary, NetworkParams,
+    };
+
+    pub(crate) fn input(input: Input) -> module::Input {
+        module::Input { input: Some(input) }
+    }
+
+    pub(crate) fn map_module(name: &str, inputs: Vec<Input>) -> Module {
+        Module {
+            name: name.to_string(),
+            inputs: inputs.into_iter().map(input).collect(),
+            kind: Some(module::Kind::KindMap(module::KindMap {
+                output_type: "proto:test.Output".to_string(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    fn modules() -> Modules {
+        Modules {
+            modules: vec![
+                map_module(
+                    "map_transfers",
+                    vec![
+                        Input::Params(Params {
+                            value: "0xdefault".to_string(),
+                        }),
+                        Input::Source(Source {
+                            r#type: "sf.ethereum.type.v2.Block".to_string(),
+                        }),
+                    ],
+                ),
+                map_module(
+                    "map_pools",
+                    vec![Input::Map(Map {
+                        module_name: "map_transfers".to_string(),
+                    })],
+                ),
+            ],
+            binaries: vec![Binary {
+                r#type: "wasm/rust-v1".to_string(),
+                content: b"\0asm".to_vec(),
+            }],
+        }
+    }
+
+    fn params_value(modules: &Modules, name: &str) -> Option<String> {
+        params_input(find_module(modules, name).unwrap()).map(|p| p.value.clone())
+    }
+
+    #[test]
+    fn applies_params() {
+        let mut modules = modules();
+        apply_params(
+            &mut modules,
+            &[("map_transfers".to_string(), "0xabc".to_string())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            params_value(&modules, "map_transfers"),
+            Some("0xabc".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_params() {
+        let mut modules = modules();
+
+        let err = apply_params(
+            &mut modules,
+            &[
+                ("map_transfers".to_string(), "0xabc".to_string()),
+                ("map_unknown".to_string(), "".to_string()),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "module 'map_unknown' not found in package");
+
+        let err =
+            apply_params(&mut modules, &[("map_pools".to_string(), "".to_string())]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "module 'map_pools' does not take a params input"
+        );
+
+        // Nothing is applied when any of the params is invalid
+        assert_eq!(
+            params_value(&modules, "map_transfers"),
+            Some("0xdefault".to_string())
+        );
+    }
+
+    fn network(initial_block: u64, address: &str) -> NetworkParams {
+        NetworkParams {
+            initial_blocks: HashMap::from([("map_transfers".to_string(), initial_block)]),
+            params: HashMap::from([("map_transfers".to_string(), address.to_string())]),
+        }
+    }
+
+    fn package() -> Package {
+        Package {
+            modules: Some(modules()),
+            network: "mainnet".to_string(),
+            networks: HashMap::from([
+                ("mainnet".to_string(), network(12_000_000, "0xmainnet")),
+                ("sepolia".to_string(), network(4_000_000, "0xsepolia")),
+            ]),
+            ..Default::default()
+        }
+    }
+
+    fn initial_block_and_params(package: &Package) -> (u64, Option<String>) {
+        let modules = package.modules.as_ref().unwrap();
+        (
+            find_module(modules, "map_transfers").unwrap().initial_block,
+            params_value(modules, "map_transfers"),
+        )
+    }
+
+    #[test]
+    fn applies_network() {
+        let mut default = package();
+        apply_network(&mut default, None).unwrap();
+        assert_eq!(default.network, "mainnet");
+        assert_eq!(
+            initial_block_and_params(&default),
+            (12_000_000, Some("0xmainnet".to_string()))
+        );
+
+        let mut sepolia = package();
+        apply_network(&mut sepolia, Some("sepolia")).unwrap();
+        assert_eq!(sepolia.network, "sepolia");
+        assert_eq!(
+            initial_block_and_params(&sepolia),
+            (4_000_000, Some("0xsepolia".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_undeclared_network() {
+        let err = apply_network(&mut package(), Some("base")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "network 'base' is not declared in package, declared networks: mainnet, sepolia"
+        );
+
+        let mut no_default = package();
+        no_default.network = "".to_string();
+        assert!(apply_network(&mut no_default, None).is_err());
+
+        let mut single_network = package();
+        single_network.networks.clear();
+        apply_network(&mut single_network, None).unwrap();
+        apply_network(&mut single_network, Some("mainnet")).unwrap();
+        assert!(apply_network(&mut single_network, Some("base")).is_err());
+    }
+
+    #[test]
+    fn module_hashes() {
+        let modules = modules();
+        let transfers = module_hash(&modules, "map_transfers").unwrap();
+        let pools = module_hash(&modules, "map_pools").unwrap();
+
+        assert_eq!(transfers.len(), 40);
+        assert_ne!(transfers, pools);
+        assert_eq!(module_hash(&modules, "map_transfers").unwrap(), transfers);
+
+        // Changing an ancestor's params changes the hash of its dependents too
+        let mut changed = modules.clone();
+        apply_params(
+            &mut changed,
+            &[("map_transfers".to_string(), "0xabc".to_string())],
+        )
+        .unwrap();
+        assert_ne!(module_hash(&changed, "map_transfers").unwrap(), transfers);
+        assert_ne!(module_hash(&changed, "map_pools").unwrap(), pools);
+
+        // Adding an unrelated module changes nothing
+        let mut extended = modules.clone();
+        extended.modules.push(map_module("map_other", vec![]));
+        assert_eq!(module_hash(&extended, "map_pools").unwrap(), pools);
+    }
+
+    #[test]
+    fn module_hash_follows_reference_layout() {
+        // Not a vector from a published package: the preimages spell out the layout
+        // of the reference implementation (manifest/signature.go) field by field.
+        let transfers = Sha1::digest(
+            [
+                b"initial_block0".as_slice(),
+                b"kindmap",
+                b"binarywasm/rust-v1\0asm",
+                b"inputsparams0xdefaultsourcesf.ethereum.type.v2.Block",
+                b"ancestors",
+                b"entrypoint",
+            ]
+            .concat(),
+        );
+        let pools = Sha1::digest(
+            [
+                b"initial_block0".as_slice(),
+                b"kindmap",
+                b"binarywasm/rust-v1\0asm",
+                b"inputsmapmap_transfers",
+                b"ancestors",
+                transfers.as_slice(),
+                b"entrypoint",
+            ]
+            .concat(),
+        );
+
+        let modules = modules();
+        assert_eq!(
+            module_hash(&modules, "map_transfers").unwrap(),
+            format!("{:x}", transfers)
+        );
+        assert_eq!(
+            module_hash(&modules, "map_pools").unwrap(),
+            format!("{:x}", pools)
+        );
+    }
+
+    #[test]
+    fn ancestors_detect_cycles_and_dangling_references() {
+        let mut modules = modules();
+        assert_eq!(
+            ancestors(&modules, "map_pools").unwrap(),
+            BTreeSet::from(["map_transfers".to_string()])
+        );
+
+        modules.modules.push(map_module(
+            "map_dangling",
+            vec![Input::Map(Map {
+                module_name: "map_unknown".to_string(),
+            })],
+        ));
+        assert_eq!(
+            ancestors(&modules, "map_dangling").unwrap_err().to_string(),
+            "module 'map_dangling' depends on unknown module 'map_unknown'"
+        );
+
+        find_module_mut(&mut modules, "map_transfers")
+            .unwrap()
+            .inputs
+            .push(input(Input::Store(Store {
+                module_name: "map_pools".to_string(),
+                mode: store::Mode::Get as i32,
+            })));
+        assert_eq!(
+            ancestors(&modules, "map_pools").unwrap_err().to_string(),
+            "module graph has a cycle: map_pools -> map_transfers -> map_pools"
+        );
+    }
+
+    #[test]
+    fn prunes_unused_modules_and_binaries() {
+        let mut modules = modules();
+        modules.binaries.push(Binary {
+            r#type: "wasm/rust-v1".to_string(),
+            content: b"\0asm pools".to_vec(),
+        });
+        modules.binaries.push(Binary {
+            r#type: "wasm/rust-v1".to_string(),
+            content: b"\0asm other".to_vec(),
+        });
+        find_module_mut(&mut modules, "map_pools")
+            .unwrap()
+            .binary_index = 1;
+        let mut other = map_module("map_other", vec![]);
+        other.binary_index = 2;
+        modules.modules.push(other);
+
+        let pruned = prune_modules(&modules, &["map_pools"]).unwrap();
+        assert_eq!(
+            pruned
+                .modules
+                .iter()
+                .map(|m| (m.name.as_str(), m.binary_index))
+                .collect::<Vec<_>>(),
+            vec![("map_transfers", 0), ("map_pools", 1)]
+        );
+        assert_eq!(pruned.binaries, modules.binaries[..2].to_vec());
+        assert_eq!(
+            module_hash(&pruned, "map_pools").unwrap(),
+            module_hash(&modules, "map_pools").unwrap()
+        );
+
+        let pruned = prune_modules(&modules, &["map_other"]).unwrap();
+        assert_eq!(pruned.modules.len(), 1);
+        assert_eq!(pruned.modules[0].binary_index, 0);
+        assert_eq!(pruned.binaries, modules.binaries[2..].to_vec());
+
+        assert!(prune_modules(&modules, &["map_unknown"]).is_err());
+    }
+}
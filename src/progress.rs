@@ -0,0 +1,342 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use crate::pb::sf::substreams::rpc::v2::{
+    BlockRange, Job, ModuleStats, ModulesProgress, ProcessedBytes, SessionInit,
+};
+
+/// Parallel processing progress reported by the server, received as
+/// `BlockResponse::Progress` until the linear handoff block is reached.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// Modules are processed in stages, each stage depending on the previous ones
+    pub stages: Vec<StageProgress>,
+    pub running_jobs: Vec<Job>,
+    pub modules_stats: Vec<ModuleStats>,
+    pub processed_bytes: ProcessedBytes,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StageProgress {
+    pub modules: Vec<String>,
+    /// Sorted and merged `[start_block, end_block)` ranges
+    pub completed_ranges: Vec<BlockRange>,
+}
+
+impl From<ModulesProgress> for Progress {
+    fn from(progress: ModulesProgress) -> Self {
+        Progress {
+            stages: progress
+                .stages
+                .into_iter()
+                .map(|stage| StageProgress {
+                    modules: stage.modules,
+                    completed_ranges: merge_ranges(stage.completed_ranges),
+                })
+                .collect(),
+            running_jobs: progress.running_jobs,
+            modules_stats: progress.modules_stats,
+            processed_bytes: progress.processed_bytes.unwrap_or_default(),
+        }
+    }
+}
+
+impl Progress {
+    /// Block up to which `module` was processed without gaps, as reported by the server.
+    pub fn highest_contiguous_block(&self, module: &str) -> Option<u64> {
+        self.modules_stats
+            .iter()
+            .find(|stats| stats.name == module)
+            .map(|stats| stats.highest_contiguous_block)
+    }
+}
+
+impl StageProgress {
+    /// Number of completed blocks within `[start_block, stop_block)`.
+    pub fn completed_blocks(&self, start_block: u64, stop_block: u64) -> u64 {
+        self.completed_ranges
+            .iter()
+            .map(|range| {
+                range
+                    .end_block
+                    .min(stop_block)
+                    .saturating_sub(range.start_block.max(start_block))
+            })
+            .sum()
+    }
+}
+
+fn merge_ranges(mut ranges: Vec<BlockRange>) -> Vec<BlockRange> {
+    ranges.sort_by_key(|range| range.start_block);
+
+    let mut merged: Vec<BlockRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start_block <= last.end_block => {
+                last.end_block = last.end_block.max(range.end_block)
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+/// Follows the progress events of a stream to estimate the processing speed and the
+/// time left until the linear handoff block, from which blocks are streamed live.
+pub struct ProgressTracker {
+    start_block: u64,
+    linear_handoff_block: u64,
+    window: Duration,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+/// What [ProgressTracker::update] computed from a progress event, displayed on a
+/// single line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressSummary {
+    pub stages: Vec<StageSummary>,
+    pub running_jobs: usize,
+    pub blocks_per_second: Option<f64>,
+    /// Estimated time left until the linear handoff block
+    pub eta: Option<Duration>,
+    pub linear_handoff_block: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StageSummary {
+    pub modules: Vec<String>,
+    /// Between 0 and 1
+    pub completion: f64,
+}
+
+impl Default for ProgressTracker {
+    fn default() -> Self {
+        ProgressTracker {
+            start_block: 0,
+            linear_handoff_block: 0,
+            window: Duration::from_secs(10),
+            samples: VecDeque::new(),
+        }
+    }
+}
+
+impl ProgressTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks per second are averaged over the progress events received during the
+    /// last `window`.
+    pub fn with_window(window: Duration) -> Self {
+        ProgressTracker {
+            window,
+            ..Default::default()
+        }
+    }
+
+    /// Progress is relative to the session's block range, starts over on reconnection.
+    pub fn handle_session(&mut self, session: &SessionInit) {
+        self.start_block = session.resolved_start_block;
+        self.linear_handoff_block = session.linear_handoff_block;
+        self.samples.clear();
+    }
+
+    pub fn update(&mut self, progress: &Progress, now: Instant) -> ProgressSummary {
+        let mut completed = 0;
+        let mut remaining = 0;
+
+        let stages = progress
+            .stages
+            .iter()
+            .map(|stage| {
+                // Stores are processed from their initial block, which can be below the
+                // start block of the request
+                let start_block = stage
+                    .completed_ranges
+                    .first()
+                    .map_or(self.start_block, |range| {
+                        range.start_block.min(self.start_block)
+                    });
+                let total = self.linear_handoff_block.saturating_sub(start_block);
+                let done = stage.completed_blocks(start_block, self.linear_handoff_block);
+
+                completed += done;
+                remaining += total - done;
+
+                StageSummary {
+                    modules: stage.modules.clone(),
+                    completion: match total {
+                        0 => 1.0,
+                        _ => done as f64 / total as f64,
+                    },
+                }
+            })
+            .collect();
+
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, completed));
+
+        let (first_at, first_completed) = self.samples.front().expect("just pushed");
+        let elapsed = now.duration_since(*first_at).as_secs_f64();
+        let blocks_per_second = match elapsed > 0.0 {
+            true => Some(completed.saturating_sub(*first_completed) as f64 / elapsed),
+            false => None,
+        };
+
+        let eta = match (remaining, blocks_per_second) {
+            (0, _) => Some(Duration::ZERO),
+            (_, Some(rate)) if rate > 0.0 => Some(Duration::from_secs_f64(remaining as f64 / rate)),
+            _ => None,
+        };
+
+        ProgressSummary {
+            stages,
+            running_jobs: progress.running_jobs.len(),
+            blocks_per_second,
+            eta,
+            linear_handoff_block: self.linear_handoff_block,
+        }
+    }
+}
+
+impl Display for ProgressSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, stage) in self.stages.iter().enumerate() {
+            let modules = match stage.modules.as_slice() {
+                [] => "-".to_string(),
+                [module] => module.clone(),
+                [module, others @ ..] => format!("{} +{}", module, others.len()),
+            };
+            write!(
+                f,
+                "Stage {} ({}) {:.1}% | ",
+                i,
+                modules,
+                stage.completion * 100.0
+            )?;
+        }
+
+        write!(f, "{} jobs", self.running_jobs)?;
+        if let Some(blocks_per_second) = self.blocks_per_second {
+            write!(f, " | {:.0} blocks/s", blocks_per_second)?;
+        }
+        match self.eta {
+            Some(eta) => write!(
+                f,
+                " | ETA {} to #{}",
+                format_duration(eta),
+                self.linear_handoff_block
+            ),
+            None => write!(f, " | ETA unknown to #{}", self.linear_handoff_block),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, (seconds % 3600) / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pb::sf::substreams::rpc::v2::Stage;
+
+    fn range(start_block: u64, end_block: u64) -> BlockRange {
+        BlockRange {
+            start_block,
+            end_block,
+        }
+    }
+
+    fn progress(stages: Vec<(&str, Vec<BlockRange>)>) -> Progress {
+        Progress::from(ModulesProgress {
+            stages: stages
+                .into_iter()
+                .map(|(module, completed_ranges)| Stage {
+                    modules: vec![module.to_string()],
+                    completed_ranges,
+                })
+                .collect(),
+            running_jobs: vec![Job::default(); 2],
+            modules_stats: vec![ModuleStats {
+                name: "store_pools".to_string(),
+                highest_contiguous_block: 2000,
+                ..Default::default()
+            }],
+            processed_bytes: None,
+        })
+    }
+
+    #[test]
+    fn summarizes_modules_progress() {
+        let progress = progress(vec![(
+            "store_pools",
+            vec![
+                range(2000, 3000),
+                range(0, 1000),
+                range(1000, 2000),
+                range(4000, 5000),
+            ],
+        )]);
+
+        assert_eq!(
+            progress.stages[0].completed_ranges,
+            vec![range(0, 3000), range(4000, 5000)]
+        );
+        assert_eq!(progress.stages[0].completed_blocks(1000, 4500), 2500);
+        assert_eq!(progress.highest_contiguous_block("store_pools"), Some(2000));
+        assert_eq!(progress.highest_contiguous_block("map_swaps"), None);
+    }
+
+    #[test]
+    fn tracks_completion_rate_and_eta() {
+        let mut tracker = ProgressTracker::new();
+        tracker.handle_session(&SessionInit {
+            resolved_start_block: 1000,
+            linear_handoff_block: 11_000,
+            ..Default::default()
+        });
+
+        let start = Instant::now();
+        let summary = tracker.update(
+            &progress(vec![
+                ("store_pools", vec![range(0, 1000)]),
+                ("map_swaps", vec![]),
+            ]),
+            start,
+        );
+        assert_eq!(summary.stages[0].completion, 1000.0 / 11_000.0);
+        assert_eq!(summary.stages[1].completion, 0.0);
+        assert_eq!(summary.blocks_per_second, None);
+        assert_eq!(summary.eta, None);
+
+        let summary = tracker.update(
+            &progress(vec![
+                ("store_pools", vec![range(0, 6000)]),
+                ("map_swaps", vec![range(1000, 6000)]),
+            ]),
+            start + Duration::from_secs(5),
+        );
+        assert_eq!(summary.blocks_per_second, Some(2000.0));
+        // 5000 blocks left for store_pools, 5000 for map_swaps
+        assert_eq!(summary.eta, Some(Duration::from_secs(5)));
+        assert_eq!(
+            summary.to_string(),
+            "Stage 0 (store_pools) 54.5% | Stage 1 (map_swaps) 50.0% | 2 jobs | 2000 blocks/s | ETA 5s to #11000"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{format_err, Error};
+use futures03::{Stream, StreamExt};
+
+use crate::pb::sf::substreams::{rpc::v2::BlockScopedData, v1::BlockRef};
+use crate::substreams_stream::BlockResponse;
+
+/// Wraps a stream of `BlockResponse` and only releases blocks once they can no
+/// longer be reverted, for consumers that are not able to roll data back.
+///
+/// `BlockResponse::New` items are held in memory until the block's number is
+/// below or equal to the `final_block_height` received from the server, and are
+/// dropped when a `BlockResponse::Undo` reverts them. Undo signals are never
+/// emitted, `BlockResponse::Session` and `BlockResponse::Progress` items are
+/// emitted as soon as received. Contrary to requesting `final_blocks_only`, the
+/// stream still follows the live head so buffered blocks are released as soon as
+/// they become final.
+///
+/// A custom finality depth can be set with [ReorgBuffer::with_depth], blocks are
+/// then also released once `depth` blocks have been received on top of them. An
+/// undo signal reverting an already released block ends the stream with an error.
+pub struct ReorgBuffer<S> {
+    stream: S,
+    buffer: ForkBuffer,
+    released: VecDeque<BlockScopedData>,
+}
+
+impl<S> ReorgBuffer<S>
+where
+    S: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+{
+    pub fn new(stream: S) -> Self {
+        ReorgBuffer {
+            stream,
+            buffer: ForkBuffer::new(None),
+            released: VecDeque::new(),
+        }
+    }
+
+    pub fn with_depth(stream: S, depth: u64) -> Self {
+        ReorgBuffer {
+            stream,
+            buffer: ForkBuffer::new(Some(depth)),
+            released: VecDeque::new(),
+        }
+    }
+}
+
+impl<S> Stream for ReorgBuffer<S>
+where
+    S: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+{
+    type Item = Result<BlockResponse, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(data) = self.released.pop_front() {
+                return Poll::Ready(Some(Ok(BlockResponse::New(data))));
+            }
+
+            let response = match self.stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(
+                    response @ (BlockResponse::Session(_) | BlockResponse::Progress(_)),
+                ))) => return Poll::Ready(Some(Ok(response))),
+                Poll::Ready(Some(Ok(response))) => response,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    if !self.buffer.is_empty() {
+                        tracing::warn!(
+                            buffered = self.buffer.len(),
+                            "Stream ended with non-final blocks still buffered, they are not released"
+                        );
+                    }
+
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+
+            let this = &mut *self;
+            if let Err(err) = this.buffer.push(response, &mut this.released) {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+    }
+}
+
+struct ForkBuffer {
+    depth: Option<u64>,
+    blocks: VecDeque<(u64, BlockScopedData)>,
+    last_released: Option<BlockRef>,
+}
+
+impl ForkBuffer {
+    fn new(depth: Option<u64>) -> Self {
+        ForkBuffer {
+            depth,
+            blocks: VecDeque::new(),
+            last_released: None,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Applies the response to the buffer, appending every block that became
+    /// releasable to `released`, in order.
+    fn push(
+        &mut self,
+        response: BlockResponse,
+        released: &mut VecDeque<BlockScopedData>,
+    ) -> Result<(), Error> {
+        match response {
+            BlockResponse::New(data) => {
+                let clock = data
+                    .clock
+                    .as_ref()
+                    .ok_or_else(|| format_err!("received block scoped data without clock"))?;
+                let head = clock.number;
+
+                let mut release_up_to = data.final_block_height;
+                if let Some(depth) = self.depth {
+                    release_up_to = release_up_to.max(head.saturating_sub(depth));
+                }
+
+                self.blocks.push_back((head, data));
+
+                while let Some((block_num, _)) = self.blocks.front() {
+                    if *block_num > release_up_to {
+                        break;
+                    }
+
+                    let (_, data) = self.blocks.pop_front().expect("front exists");
+                    let clock = data.clock.as_ref().expect("checked on push");
+                    self.last_released = Some(BlockRef {
+                        id: clock.id.clone(),
+                        number: clock.number,
+                    });
+                    released.push_back(data);
+                }
+            }
+            BlockResponse::Undo(signal) => {
+                let last_valid_block = signal.last_valid_block.ok_or_else(|| {
+                    format_err!("received block undo signal without last valid block")
+                })?;
+
+                if let Some(released) = &self.last_released {
+                    if released.number > last_valid_block.number {
+                        return Err(format_err!(
+                            "undo signal reverts to block #{} but block #{} ({}) was already released, finality depth is too small",
+                            last_valid_block.number,
+                            released.number,
+                            released.id
+                        ));
+                    }
+                }
+
+                self.blocks
+                    .retain(|(block_num, _)| *block_num <= last_valid_block.number);
+            }
+            // Emitted right away by `ReorgBuffer`, nothing to buffer
+            BlockResponse::Session(_) | BlockResponse::Progress(_) => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures03::{executor::block_on, stream};
+
+    use super::*;
+    use crate::pb::sf::substreams::rpc::v2::SessionInit;
+    use crate::sink::tests::{block, undo};
+
+    fn released_ids(
+        buffer: ReorgBuffer<impl Stream<Item = Result<BlockResponse, Error>> + Unpin>,
+    ) -> Vec<String> {
+        block_on(buffer.collect::<Vec<_>>())
+            .into_iter()
+            .map(|response| match response.unwrap() {
+                BlockResponse::New(data) => data.clock.unwrap().id,
+                BlockResponse::Undo(_) => panic!("undo signals must never be released"),
+                BlockResponse::Session(session) => format!("session {}", session.trace_id),
+                BlockResponse::Progress(_) => "progress".to_string(),
+            })
+            .collect()
+    }
+
+    fn session(trace_id: &str) -> BlockResponse {
+        BlockResponse::Session(SessionInit {
+            trace_id: trace_id.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn releases_blocks_once_final_and_drops_undone_ones() {
+        let responses = vec![
+            session("t1"),
+            block(1, "1a", 0),
+            block(2, "2a", 1),
+            block(3, "3a", 1),
+            block(4, "4a", 1),
+            undo(2, "2a"),
+            block(3, "3b", 2),
+            block(4, "4b", 3),
+            session("t2"),
+            block(5, "5b", 3),
+        ];
+
+        let buffer = ReorgBuffer::new(stream::iter(responses.into_iter().map(Ok)));
+        assert_eq!(
+            released_ids(buffer),
+            vec!["session t1", "1a", "2a", "3b", "session t2"]
+        );
+    }
+
+    #[test]
+    fn with_depth_releases_before_finality() {
+        let responses = vec![
+            block(1, "1a", 0),
+            block(2, "2a", 0),
+            block(3, "3a", 0),
+            block(4, "4a", 0),
+            undo(3, "3a"),
+            block(4, "4b", 0),
+        ];
+
+        let buffer = ReorgBuffer::with_depth(stream::iter(responses.into_iter().map(Ok)), 2);
+        assert_eq!(released_ids(buffer), vec!["1a", "2a"]);
+    }
+
+    #[test]
+    fn with_depth_fails_when_released_block_is_undone() {
+        let responses = vec![
+            block(1, "1a", 0),
+            block(2, "2a", 0),
+            block(3, "3a", 0),
+            undo(1, "1a"),
+        ];
+
+        let mut buffer = ReorgBuffer::with_depth(stream::iter(responses.into_iter().map(Ok)), 1);
+        let results = block_on(async {
+            let mut results = vec![];
+            while let Some(result) = buffer.next().await {
+                results.push(result);
+            }
+            results
+        });
+
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{format_err, Context, Error};
+
+use crate::cursor::{CursorKey, CursorStore};
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::BlockRef,
+};
+
+use super::Sink;
+
+/// Discards module outputs and only persists the cursor of each block through a
+/// [CursorStore]. Useful when outputs are processed elsewhere, by a wrapping sink
+/// for example, and have nothing to roll back.
+pub struct CursorSink<C: CursorStore> {
+    store: C,
+    key: CursorKey,
+}
+
+impl<C: CursorStore> CursorSink<C> {
+    pub fn new(store: C, key: CursorKey) -> Self {
+        CursorSink { store, key }
+    }
+}
+
+impl<C: CursorStore> Sink for CursorSink<C> {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        self.store
+            .save(&self.key, &data.cursor)
+            .context(format!("persist cursor for {}", self.key))
+    }
+
+    fn undo(&mut self, _last_valid_block: &BlockRef) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        if signal.last_valid_block.is_none() {
+            return Err(format_err!(
+                "received block undo signal without last valid block"
+            ));
+        }
+
+        self.store
+            .save(&self.key, &signal.last_valid_cursor)
+            .context(format!("persist cursor for {}", self.key))
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        self.store
+            .load(&self.key)
+            .context(format!("load persisted cursor for {}", self.key))
+    }
+}
@@ -0,0 +1,403 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{format_err, Context, Error};
+
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::BlockRef,
+};
+
+use super::Sink;
+
+/// Appends one line per block to a file, in the form
+/// `<block_num> <block_id> <final_block_height> <cursor> <type_url> <payload as hex>`.
+///
+/// Each line is synced to disk before `handle_block` returns and carries its own
+/// cursor, so data and cursor can never get out of sync: on restart, the cursor of
+/// the last line is used to resume. The offset of every reversible block (above
+/// the last seen `final_block_height`) is kept so that undo simply truncates the
+/// file back to the first line above the last valid block.
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+    offset: u64,
+    /// Offset of the last line that is not reversible anymore
+    final_line: Option<u64>,
+    reversible: VecDeque<(u64, u64)>,
+    cursor: Option<String>,
+}
+
+impl FileSink {
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .context(format!("open output file '{}'", path.display()))?;
+
+        let scanned = scan(&path, &file)?;
+
+        // A crash in the middle of a write can leave a partial line at the end
+        file.set_len(scanned.offset)
+            .context(format!("truncate output file '{}'", path.display()))?;
+        file.seek(SeekFrom::Start(scanned.offset))?;
+
+        Ok(FileSink {
+            path,
+            file,
+            offset: scanned.offset,
+            final_line: scanned.final_line,
+            reversible: scanned.reversible,
+            cursor: scanned.cursor,
+        })
+    }
+
+    /// Reads the cursor of the last complete line without creating or modifying the
+    /// file, which must exist.
+    pub fn read_cursor<P: AsRef<Path>>(path: P) -> Result<Option<String>, Error> {
+        let path = path.as_ref();
+        let file = File::open(path).context(format!("open output file '{}'", path.display()))?;
+
+        Ok(scan(path, &file)?.cursor)
+    }
+
+    fn read_line_at(&mut self, offset: u64) -> Result<String, Error> {
+        let mut line = String::new();
+        self.file.seek(SeekFrom::Start(offset))?;
+        BufReader::new(&mut self.file)
+            .read_line(&mut line)
+            .context(format!("read output file '{}'", self.path.display()))?;
+        self.file.seek(SeekFrom::Start(self.offset))?;
+
+        Ok(line)
+    }
+}
+
+impl Sink for FileSink {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        let clock = data
+            .clock
+            .as_ref()
+            .ok_or_else(|| format_err!("received block scoped data without clock"))?;
+
+        let (type_url, payload) = match data.output.as_ref().and_then(|o| o.map_output.as_ref()) {
+            Some(any) => (any.type_url.as_str(), any.value.as_slice()),
+            None => ("", [].as_slice()),
+        };
+
+        let mut line = format!(
+            "{} {} {} {} {} ",
+            clock.number, clock.id, data.final_block_height, data.cursor, type_url
+        );
+        for byte in payload {
+            write!(line, "{:02x}", byte).expect("writing to a String never fails");
+        }
+        line.push('\n');
+
+        self.file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.sync_data())
+            .context(format!(
+                "write block #{} to '{}'",
+                clock.number,
+                self.path.display()
+            ))?;
+
+        self.reversible.push_back((clock.number, self.offset));
+        self.offset += line.len() as u64;
+        self.cursor = Some(data.cursor.clone());
+        prune(
+            &mut self.reversible,
+            &mut self.final_line,
+            data.final_block_height,
+        );
+
+        Ok(())
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        let Some(position) = self
+            .reversible
+            .iter()
+            .position(|(block_num, _)| *block_num > last_valid_block.number)
+        else {
+            return Ok(());
+        };
+
+        let (_, offset) = self.reversible[position];
+        self.file
+            .set_len(offset)
+            .and_then(|_| self.file.sync_data())
+            .context(format!(
+                "truncate '{}' after block #{}",
+                self.path.display(),
+                last_valid_block.number
+            ))?;
+
+        self.offset = offset;
+        self.reversible.truncate(position);
+
+        // The cursor to resume from is now the one of the last remaining line
+        let last_line = self
+            .reversible
+            .back()
+            .map(|(_, offset)| *offset)
+            .or(self.final_line);
+
+        self.cursor = match last_line {
+            Some(offset) => {
+                let line = self.read_line_at(offset)?;
+                Some(parse_line(&line, &self.path, offset)?.cursor)
+            }
+            None => None,
+        };
+
+        self.file.seek(SeekFrom::Start(self.offset))?;
+        Ok(())
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        let last_valid_block = signal
+            .last_valid_block
+            .as_ref()
+            .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
+
+        self.undo(last_valid_block)?;
+
+        // Equivalent to the cursor of the last line when the last valid block was
+        // received by this sink, but also correct when it was not.
+        self.cursor = Some(signal.last_valid_cursor.clone());
+        Ok(())
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        Ok(self.cursor.clone())
+    }
+}
+
+/// Forgets about lines that are final, they can never be undone.
+fn prune(
+    reversible: &mut VecDeque<(u64, u64)>,
+    final_line: &mut Option<u64>,
+    final_block_height: u64,
+) {
+    while let Some((block_num, offset)) = reversible.front() {
+        if *block_num > final_block_height {
+            break;
+        }
+
+        *final_line = Some(*offset);
+        reversible.pop_front();
+    }
+}
+
+const LEGACY_FIELDS: usize = 4;
+
+struct Line {
+    block_num: u64,
+    final_block_height: u64,
+    cursor: String,
+}
+
+fn parse_line(line: &str, path: &Path, offset: u64) -> Result<Line, Error> {
+    // Lines used to be `<block_num> <block_id> <type_url> <payload as hex>`, with the
+    // cursor stored apart. Resuming from such a file would need that cursor, which the
+    // file cannot be matched against.
+    if line.split(' ').count() == LEGACY_FIELDS {
+        return Err(format_err!(
+            "output file '{}' was written by a previous version without per-line cursors (line at offset {}), move it away to start a new one",
+            path.display(),
+            offset
+        ));
+    }
+
+    let mut fields = line.split(' ');
+
+    let block_num = fields.next().and_then(|x| x.parse::<u64>().ok());
+    let final_block_height = fields.nth(1).and_then(|x| x.parse::<u64>().ok());
+    let cursor = fields.next();
+
+    match (block_num, final_block_height, cursor) {
+        (Some(block_num), Some(final_block_height), Some(cursor)) => Ok(Line {
+            block_num,
+            final_block_height,
+            cursor: cursor.to_string(),
+        }),
+        _ => Err(format_err!(
+            "output file '{}' is corrupted at offset {}",
+            path.display(),
+            offset
+        )),
+    }
+}
+
+struct Scanned {
+    offset: u64,
+    final_line: Option<u64>,
+    reversible: VecDeque<(u64, u64)>,
+    cursor: Option<String>,
+}
+
+/// Reads back an existing output file, returning the offset right after the last
+/// complete line along with the offset of each reversible line and the cursor of
+/// the last one.
+fn scan(path: &Path, file: &File) -> Result<Scanned, Error> {
+    let mut reader = BufReader::new(file);
+    let mut scanned = Scanned {
+        offset: 0,
+        final_line: None,
+        reversible: VecDeque::new(),
+        cursor: None,
+    };
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let read = reader
+            .read_line(&mut line)
+            .context(format!("read output file '{}'", path.display()))?;
+
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+
+        let parsed = parse_line(&line, path, scanned.offset)?;
+        scanned
+            .reversible
+            .push_back((parsed.block_num, scanned.offset));
+        prune(
+            &mut scanned.reversible,
+            &mut scanned.final_line,
+            parsed.final_block_height,
+        );
+
+        scanned.cursor = Some(parsed.cursor);
+        scanned.offset += read as u64;
+    }
+
+    Ok(scanned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::tests::{block, forked_chain, replay, undo};
+    use super::*;
+
+    fn block_ids(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.split(' ').nth(1).unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn undo_truncates_lines_above_last_valid_block() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+
+        let mut sink = FileSink::open(&path).unwrap();
+        replay(&mut sink, forked_chain()).unwrap();
+
+        assert_eq!(block_ids(&path), vec!["1a", "2a", "3a", "4b", "5b"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-5b".to_string()));
+    }
+
+    #[test]
+    fn undo_after_reopen_uses_existing_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+
+        let mut sink = FileSink::open(&path).unwrap();
+        replay(&mut sink, vec![block(1, "1a", 0), block(2, "2a", 0)]).unwrap();
+        drop(sink);
+
+        let mut sink = FileSink::open(&path).unwrap();
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-2a".to_string()));
+
+        replay(&mut sink, vec![undo(1, "1a")]).unwrap();
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-1a".to_string()));
+
+        replay(&mut sink, vec![block(2, "2b", 0)]).unwrap();
+        assert_eq!(block_ids(&path), vec!["1a", "2b"]);
+    }
+
+    #[test]
+    fn plain_undo_restores_cursor_of_last_remaining_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+
+        let mut sink = FileSink::open(&path).unwrap();
+        replay(
+            &mut sink,
+            vec![block(1, "1a", 0), block(2, "2a", 1), block(3, "3a", 1)],
+        )
+        .unwrap();
+
+        sink.undo(&BlockRef {
+            id: "1a".to_string(),
+            number: 1,
+        })
+        .unwrap();
+
+        assert_eq!(block_ids(&path), vec!["1a"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-1a".to_string()));
+    }
+
+    #[test]
+    fn read_cursor_leaves_file_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+
+        assert!(FileSink::read_cursor(&path).is_err());
+        assert!(!path.exists());
+
+        let content = "1 1a 0 cursor-1a type 00\n2 2a 0 curs";
+        fs::write(&path, content).unwrap();
+        assert_eq!(
+            FileSink::read_cursor(&path).unwrap(),
+            Some("cursor-1a".to_string())
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+    }
+
+    #[test]
+    fn open_rejects_legacy_line_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+        let legacy =
+            "1 1a type.googleapis.com/test.Output 00\n2 2a type.googleapis.com/test.Output \n";
+        fs::write(&path, legacy).unwrap();
+
+        let err = FileSink::open(&path)
+            .err()
+            .expect("legacy file is rejected");
+        assert!(format!("{:#}", err).contains("previous version"));
+        // The file is left untouched
+        assert_eq!(fs::read_to_string(&path).unwrap(), legacy);
+    }
+
+    #[test]
+    fn open_drops_partially_written_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("outputs.txt");
+        fs::write(&path, "1 1a 0 cursor-1a type 00\n2 2a 0 curs").unwrap();
+
+        let mut sink = FileSink::open(&path).unwrap();
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-1a".to_string()));
+
+        replay(&mut sink, vec![block(2, "2a", 0)]).unwrap();
+        assert_eq!(block_ids(&path), vec!["1a", "2a"]);
+    }
+}
@@ -0,0 +1,289 @@
+use std::{collections::VecDeque, io::Write};
+
+use anyhow::{format_err, Context, Error};
+use chrono::DateTime;
+use serde_json::{json, Value};
+
+use crate::decode::OutputDecoder;
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::BlockRef,
+};
+
+use super::Sink;
+
+/// Writes one JSON object per line for each block, with its clock, cursor, final
+/// block height, module name and decoded output (using the canonical Protobuf JSON
+/// mapping):
+///
+/// ```json
+/// {"clock":{"number":1,"id":"...","timestamp":"2023-..."},"cursor":"...","final_block_height":0,"module":"map_events","payload":{...}}
+/// ```
+///
+/// Undo signals are written as `{"undo":{"last_valid_block":{...},"last_valid_cursor":"..."}}`
+/// records, consumers must revert what they recorded above the last valid block.
+/// `run_sink` always goes through [Sink::handle_undo]. When [Sink::undo] is called
+/// directly, the cursor of the last written block at or below the last valid block
+/// is used instead, the cursors of reversible blocks (above the last seen
+/// `final_block_height`) are kept for that. Outputs that cannot be decoded are
+/// written with a `null` payload and an `error` field. The cursor is only kept in
+/// memory.
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+    decoder: OutputDecoder,
+    cursor: Option<String>,
+    /// Cursor of the last written block that is not reversible anymore
+    final_cursor: Option<String>,
+    reversible: VecDeque<(u64, String)>,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W, decoder: OutputDecoder) -> Self {
+        JsonLinesSink {
+            writer,
+            decoder,
+            cursor: None,
+            final_cursor: None,
+            reversible: VecDeque::new(),
+        }
+    }
+
+    pub fn block_record(&self, data: &BlockScopedData) -> Result<Value, Error> {
+        let clock = data
+            .clock
+            .as_ref()
+            .ok_or_else(|| format_err!("received block scoped data without clock"))?;
+
+        let timestamp = clock
+            .timestamp
+            .as_ref()
+            .and_then(|t| DateTime::from_timestamp(t.seconds, t.nanos as u32))
+            .map(|date| date.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true));
+
+        let output = data.output.as_ref();
+        let mut record = json!({
+            "clock": {
+                "number": clock.number,
+                "id": clock.id,
+                "timestamp": timestamp,
+            },
+            "cursor": data.cursor,
+            "final_block_height": data.final_block_height,
+            "module": output.map(|o| o.name.as_str()),
+            "payload": null,
+        });
+
+        if let Some(map_output) = output.and_then(|o| o.map_output.as_ref()) {
+            match self.decoder.decode(map_output).and_then(|message| {
+                serde_json::to_value(&message).context("convert output to JSON")
+            }) {
+                Ok(payload) => record["payload"] = payload,
+                Err(err) => record["error"] = Value::String(format!("{:#}", err)),
+            }
+        }
+
+        Ok(record)
+    }
+
+    pub fn undo_record(&self, signal: &BlockUndoSignal) -> Result<Value, Error> {
+        let last_valid_block = signal
+            .last_valid_block
+            .as_ref()
+            .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
+
+        Ok(undo_record(
+            last_valid_block,
+            Some(&signal.last_valid_cursor),
+        ))
+    }
+
+    fn write_record(&mut self, record: &Value) -> Result<(), Error> {
+        serde_json::to_writer(&mut self.writer, record).context("write JSON record")?;
+        self.writer
+            .write_all(b"\n")
+            .and_then(|_| self.writer.flush())
+            .context("write JSON record")
+    }
+}
+
+impl<W: Write> Sink for JsonLinesSink<W> {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        let record = self.block_record(data)?;
+        self.write_record(&record)?;
+
+        self.cursor = Some(data.cursor.clone());
+
+        let number = data.clock.as_ref().map_or(0, |clock| clock.number);
+        self.reversible.push_back((number, data.cursor.clone()));
+        while let Some((number, _)) = self.reversible.front() {
+            if *number > data.final_block_height {
+                break;
+            }
+
+            self.final_cursor = self.reversible.pop_front().map(|(_, cursor)| cursor);
+        }
+
+        Ok(())
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        while let Some((number, _)) = self.reversible.back() {
+            if *number <= last_valid_block.number {
+                break;
+            }
+
+            self.reversible.pop_back();
+        }
+
+        // The cursor to resume from is now the one of the last remaining block
+        self.cursor = self
+            .reversible
+            .back()
+            .map(|(_, cursor)| cursor.clone())
+            .or_else(|| self.final_cursor.clone());
+
+        // Written lines cannot be taken back, consumers are told through the undo record
+        self.write_record(&undo_record(last_valid_block, self.cursor.as_deref()))
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        let record = self.undo_record(signal)?;
+        self.write_record(&record)?;
+
+        if let Some(last_valid_block) = signal.last_valid_block.as_ref() {
+            self.reversible
+                .retain(|(number, _)| *number <= last_valid_block.number);
+        }
+
+        self.cursor = Some(signal.last_valid_cursor.clone());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().context("flush JSON writer")
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        Ok(self.cursor.clone())
+    }
+}
+
+fn undo_record(last_valid_block: &BlockRef, last_valid_cursor: Option<&str>) -> Value {
+    json!({
+        "undo": {
+            "last_valid_block": {
+                "number": last_valid_block.number,
+                "id": last_valid_block.id,
+            },
+            "last_valid_cursor": last_valid_cursor,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::super::tests::{block, replay, undo};
+    use super::*;
+    use crate::decode::tests::{package, transfer};
+    use crate::substreams_stream::BlockResponse;
+
+    fn lines(responses: Vec<BlockResponse>) -> Vec<Value> {
+        let mut sink =
+            JsonLinesSink::new(Vec::new(), OutputDecoder::from_package(&package()).unwrap());
+        replay(&mut sink, responses).unwrap();
+
+        String::from_utf8(sink.writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn writes_decoded_block_records() {
+        let mut response = block(12, "12a", 10);
+        if let BlockResponse::New(data) = &mut response {
+            data.output.as_mut().unwrap().map_output = Some(transfer());
+        }
+
+        assert_eq!(
+            lines(vec![response]),
+            vec![json!({
+                "clock": {
+                    "number": 12,
+                    "id": "12a",
+                    "timestamp": "2023-11-14T22:13:32Z",
+                },
+                "cursor": "cursor-12a",
+                "final_block_height": 10,
+                "module": "map_test",
+                "payload": {"from": "alice", "amount": "42"},
+            })]
+        );
+    }
+
+    #[test]
+    fn writes_undo_records() {
+        let records = lines(vec![undo(3, "3a")]);
+
+        assert_eq!(
+            records,
+            vec![json!({
+                "undo": {
+                    "last_valid_block": {"number": 3, "id": "3a"},
+                    "last_valid_cursor": "cursor-3a",
+                }
+            })]
+        );
+    }
+
+    #[test]
+    fn plain_undo_keeps_cursor_of_last_valid_block() {
+        let mut sink =
+            JsonLinesSink::new(Vec::new(), OutputDecoder::from_package(&package()).unwrap());
+        replay(
+            &mut sink,
+            vec![block(2, "2a", 0), block(3, "3a", 2), block(4, "4a", 2)],
+        )
+        .unwrap();
+        sink.undo(&BlockRef {
+            id: "3a".to_string(),
+            number: 3,
+        })
+        .unwrap();
+
+        let output = String::from_utf8(sink.writer.clone()).unwrap();
+        let record: Value = serde_json::from_str(output.lines().last().unwrap()).unwrap();
+        assert_eq!(
+            record,
+            json!({
+                "undo": {
+                    "last_valid_block": {"number": 3, "id": "3a"},
+                    "last_valid_cursor": "cursor-3a",
+                }
+            })
+        );
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-3a".to_string()));
+
+        // Blocks at or below the final block height are not kept but their cursor is
+        sink.undo(&BlockRef {
+            id: "2a".to_string(),
+            number: 2,
+        })
+        .unwrap();
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-2a".to_string()));
+    }
+
+    #[test]
+    fn undecodable_payload_is_reported() {
+        let records = lines(vec![block(1, "1a", 0)]);
+
+        assert_eq!(records[0]["payload"], Value::Null);
+        assert!(records[0]["error"]
+            .as_str()
+            .unwrap()
+            .contains("test.Output"));
+    }
+}
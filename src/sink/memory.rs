@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use anyhow::{format_err, Error};
+
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::BlockRef,
+};
+
+use super::Sink;
+
+/// Keeps every received block in memory, ordered by block number.
+///
+/// Mostly useful in tests or for small bounded ranges, nothing is ever released
+/// except through undo. The cursor is only kept in memory too.
+#[derive(Default, Debug)]
+pub struct MemorySink {
+    blocks: BTreeMap<u64, BlockScopedData>,
+    cursor: Option<String>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockScopedData> {
+        self.blocks.values()
+    }
+
+    pub fn block_numbers(&self) -> Vec<u64> {
+        self.blocks.keys().copied().collect()
+    }
+}
+
+impl Sink for MemorySink {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        let clock = data
+            .clock
+            .as_ref()
+            .ok_or_else(|| format_err!("received block scoped data without clock"))?;
+
+        self.blocks.insert(clock.number, data.clone());
+        self.cursor = Some(data.cursor.clone());
+        Ok(())
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        self.blocks.split_off(&(last_valid_block.number + 1));
+        self.cursor = self.blocks.values().last().map(|b| b.cursor.clone());
+        Ok(())
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        let last_valid_block = signal
+            .last_valid_block
+            .as_ref()
+            .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
+
+        self.undo(last_valid_block)?;
+        self.cursor = Some(signal.last_valid_cursor.clone());
+        Ok(())
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        Ok(self.cursor.clone())
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::{format_err, Error};
+use futures03::{Stream, StreamExt};
+use tracing::{info_span, warn};
+
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal, SessionInit},
+    v1::BlockRef,
+};
+use crate::progress::Progress;
+use crate::substreams_stream::{block_span, BlockResponse};
+
+mod cursor;
+mod file;
+mod json;
+mod memory;
+mod sqlite;
+
+pub use cursor::CursorSink;
+pub use file::FileSink;
+pub use json::JsonLinesSink;
+pub use memory::MemorySink;
+pub use sqlite::SqliteSink;
+
+/// Destination of the module outputs received from a `SubstreamsStream`.
+///
+/// A sink is also responsible for the cursor: it must persist it along with the data
+/// it belongs to, and hand it back through [Sink::cursor] so that a restarted
+/// process resumes exactly after the last block that was durably recorded.
+pub trait Sink {
+    /// Records the output of a newly received block along with its `cursor`.
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error>;
+
+    /// Called when a fork happened, the sink must remove everything it recorded for
+    /// blocks above `last_valid_block.number`. Blocks below or equal to it are still
+    /// valid and must be kept.
+    ///
+    /// It must be idempotent: if the process crashes before the undo signal's cursor
+    /// is persisted, the same undo is received again on restart.
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error>;
+
+    /// Handles an undo signal received from the stream, the cursor to resume from is
+    /// `signal.last_valid_cursor` afterwards. The default implementation only calls
+    /// [Sink::undo], sinks persisting their cursor separately from their data must
+    /// override it to persist the new cursor.
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        let last_valid_block = signal
+            .last_valid_block
+            .as_ref()
+            .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
+
+        self.undo(last_valid_block)
+    }
+
+    /// Called on every (re)connection with the session the following blocks belong to.
+    fn handle_session(&mut self, _session: &SessionInit) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called with the server's parallel processing progress, until the linear
+    /// handoff block is reached.
+    fn handle_progress(&mut self, _progress: &Progress) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Durably records everything that was handled so far, called when the stream
+    /// ends, successfully or not.
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The cursor of the last durably recorded block, `None` if nothing was recorded yet.
+    fn cursor(&self) -> Result<Option<String>, Error>;
+
+    /// Whether blocks can be handled out of block order, as done by a parallel
+    /// [Backfill](crate::backfill::Backfill) when this returns `true`.
+    fn accepts_out_of_order(&self) -> bool {
+        false
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        (**self).handle_block(data)
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        (**self).undo(last_valid_block)
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        (**self).handle_undo(signal)
+    }
+
+    fn handle_session(&mut self, session: &SessionInit) -> Result<(), Error> {
+        (**self).handle_session(session)
+    }
+
+    fn handle_progress(&mut self, progress: &Progress) -> Result<(), Error> {
+        (**self).handle_progress(progress)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        (**self).flush()
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        (**self).cursor()
+    }
+
+    fn accepts_out_of_order(&self) -> bool {
+        (**self).accepts_out_of_order()
+    }
+}
+
+/// Drives `stream` into `sink` until the stream ends. The sink is flushed when the
+/// stream completes and before returning the stream's error if it fails.
+pub async fn run_sink<S, K>(mut stream: S, sink: &mut K) -> Result<(), Error>
+where
+    S: Stream<Item = Result<BlockResponse, Error>> + Unpin,
+    K: Sink + ?Sized,
+{
+    loop {
+        match stream.next().await {
+            None => return sink.flush(),
+            Some(Ok(BlockResponse::New(data))) => {
+                block_span(&data).in_scope(|| sink.handle_block(&data))?
+            }
+            Some(Ok(BlockResponse::Undo(signal))) => {
+                let span = match &signal.last_valid_block {
+                    Some(block) => info_span!("undo", number = block.number, id = %block.id),
+                    None => info_span!("undo"),
+                };
+                span.in_scope(|| sink.handle_undo(&signal))?
+            }
+            Some(Ok(BlockResponse::Session(session))) => sink.handle_session(&session)?,
+            Some(Ok(BlockResponse::Progress(progress))) => sink.handle_progress(&progress)?,
+            Some(Err(err)) => {
+                // Record what was received so far, the cursor stays consistent with it
+                if let Err(flush_err) = sink.flush() {
+                    warn!("Unable to flush sink after stream error: {:#}", flush_err);
+                }
+
+                return Err(err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use futures03::{executor::block_on, stream};
+    use prost_types::{Any, Timestamp};
+
+    use super::*;
+    use crate::pb::sf::substreams::{rpc::v2::MapModuleOutput, v1::Clock};
+
+    pub(crate) fn block(number: u64, id: &str, final_block_height: u64) -> BlockResponse {
+        BlockResponse::New(BlockScopedData {
+            output: Some(MapModuleOutput {
+                name: "map_test".to_string(),
+                map_output: Some(Any {
+                    type_url: "type.googleapis.com/test.Output".to_string(),
+                    value: id.as_bytes().to_vec(),
+                }),
+                debug_info: None,
+            }),
+            clock: Some(Clock {
+                id: id.to_string(),
+                number,
+                timestamp: Some(Timestamp {
+                    seconds: 1_700_000_000 + number as i64,
+                    nanos: 0,
+                }),
+            }),
+            cursor: format!("cursor-{}", id),
+            final_block_height,
+            debug_map_outputs: vec![],
+            debug_store_outputs: vec![],
+        })
+    }
+
+    pub(crate) fn undo(number: u64, id: &str) -> BlockResponse {
+        BlockResponse::Undo(BlockUndoSignal {
+            last_valid_block: Some(BlockRef {
+                id: id.to_string(),
+                number,
+            }),
+            last_valid_cursor: format!("cursor-{}", id),
+        })
+    }
+
+    /// Blocks 1a to 5a, then a fork reverting everything above 3a followed by 4b and 5b.
+    pub(super) fn forked_chain() -> Vec<BlockResponse> {
+        vec![
+            block(1, "1a", 0),
+            block(2, "2a", 0),
+            block(3, "3a", 1),
+            block(4, "4a", 1),
+            block(5, "5a", 1),
+            undo(3, "3a"),
+            block(4, "4b", 2),
+            block(5, "5b", 2),
+        ]
+    }
+
+    pub(super) fn replay(sink: &mut dyn Sink, responses: Vec<BlockResponse>) -> Result<(), Error> {
+        block_on(run_sink(stream::iter(responses.into_iter().map(Ok)), sink))
+    }
+
+    #[test]
+    fn memory_sink_undo_removes_blocks_above_last_valid_block() {
+        let mut sink = MemorySink::new();
+        replay(&mut sink, forked_chain()).unwrap();
+
+        let ids: Vec<_> = sink
+            .blocks()
+            .map(|b| b.clock.as_ref().unwrap().id.clone())
+            .collect();
+        assert_eq!(ids, vec!["1a", "2a", "3a", "4b", "5b"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-5b".to_string()));
+    }
+
+    #[test]
+    fn memory_sink_undo_is_idempotent() {
+        let mut sink = MemorySink::new();
+        replay(&mut sink, forked_chain()).unwrap();
+        replay(&mut sink, vec![undo(3, "3a"), undo(3, "3a")]).unwrap();
+
+        assert_eq!(sink.block_numbers(), vec![1, 2, 3]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-3a".to_string()));
+    }
+
+    #[test]
+    fn run_sink_flushes_and_forwards_stream_error() {
+        let mut sink = SqliteSink::new(
+            rusqlite::Connection::open_in_memory().unwrap(),
+            crate::cursor::CursorKey::new("http://localhost:9000", "test.spkg", "map_test"),
+            100,
+        )
+        .unwrap();
+
+        let responses = vec![
+            Ok(block(1, "1a", 0)),
+            Ok(block(2, "2a", 0)),
+            Err(format_err!("connection lost")),
+        ];
+
+        let result = block_on(run_sink(stream::iter(responses), &mut sink));
+        assert_eq!(result.unwrap_err().to_string(), "connection lost");
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-2a".to_string()));
+    }
+}
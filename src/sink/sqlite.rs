@@ -0,0 +1,584 @@
+use std::path::Path;
+
+use anyhow::{format_err, Context, Error};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+
+use crate::cursor::{check_module_hash, CursorKey, CursorStore};
+use crate::pb::sf::substreams::{
+    rpc::v2::{BlockScopedData, BlockUndoSignal},
+    v1::BlockRef,
+};
+
+use super::Sink;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS cursors (
+        id TEXT NOT NULL PRIMARY KEY,
+        cursor TEXT NOT NULL,
+        block_num INTEGER NOT NULL,
+        block_id TEXT NOT NULL,
+        module_hash TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS outputs (
+        cursor_id TEXT NOT NULL,
+        module TEXT NOT NULL,
+        block_num INTEGER NOT NULL,
+        block_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        type_url TEXT NOT NULL,
+        payload BLOB NOT NULL,
+        PRIMARY KEY (cursor_id, module, block_num, block_id)
+    );
+";
+
+/// Writes each received module output to SQLite together with its cursor.
+///
+/// Outputs are keyed by the cursor they belong to (`cursor_id`, the [CursorKey] of the
+/// sink), so that streams of the same module from different endpoints or packages can
+/// share a database without overwriting or undoing each other's outputs.
+///
+/// Outputs and cursor are always committed within the same transaction so that a
+/// crash can never leave them out of sync: on restart, the persisted cursor points
+/// exactly after the last block whose output was committed. Blocks are buffered
+/// and committed every `batch_size` blocks, call [Sink::flush] to commit
+/// the pending ones (on shutdown for example).
+///
+/// The output module hash given to [SqliteSink::with_module_hash] is committed along
+/// with every cursor.
+pub struct SqliteSink {
+    connection: Connection,
+    key: CursorKey,
+    module_hash: Option<String>,
+    batch_size: usize,
+    pending: Vec<OutputRow>,
+    pending_blocks: usize,
+    pending_cursor: Option<PendingCursor>,
+}
+
+struct OutputRow {
+    module: String,
+    block_num: u64,
+    block_id: String,
+    timestamp: i64,
+    type_url: String,
+    payload: Vec<u8>,
+}
+
+struct PendingCursor {
+    cursor: String,
+    block_num: u64,
+    block_id: String,
+}
+
+impl SqliteSink {
+    pub fn open<P: AsRef<Path>>(path: P, key: CursorKey, batch_size: usize) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let connection =
+            Connection::open(path).context(format!("open SQLite database '{}'", path.display()))?;
+
+        Self::new(connection, key, batch_size)
+    }
+
+    /// Reads the cursor persisted for `key` without creating or modifying anything, the
+    /// database must exist.
+    pub fn read_cursor<P: AsRef<Path>>(path: P, key: &CursorKey) -> Result<Option<String>, Error> {
+        let path = path.as_ref();
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .context(format!("open SQLite database '{}'", path.display()))?;
+
+        load_cursor(&connection, key)
+    }
+
+    pub fn new(connection: Connection, key: CursorKey, batch_size: usize) -> Result<Self, Error> {
+        if batch_size == 0 {
+            return Err(format_err!("batch size must be greater than 0"));
+        }
+
+        connection
+            .execute_batch(SCHEMA)
+            .context("create SQLite schema")?;
+
+        // Outputs used to be keyed by module only, which cannot be told apart per
+        // endpoint and package anymore.
+        if !has_column(&connection, "outputs", "cursor_id")? {
+            return Err(format_err!(
+                "SQLite database was written by a previous version whose outputs are not keyed by endpoint and package, move it away to start a new one"
+            ));
+        }
+
+        // Cursors persisted before module hashes were recorded are left without one
+        if !has_column(&connection, "cursors", "module_hash")? {
+            connection
+                .execute("ALTER TABLE cursors ADD COLUMN module_hash TEXT", [])
+                .context("add module hash to SQLite cursors")?;
+        }
+
+        Ok(SqliteSink {
+            connection,
+            key,
+            module_hash: None,
+            batch_size,
+            pending: Vec::new(),
+            pending_blocks: 0,
+            pending_cursor: None,
+        })
+    }
+
+    /// Refuses to resume from the persisted cursor unless it was committed with
+    /// `output_module_hash`, which is then committed along with every new cursor.
+    pub fn with_module_hash<H: Into<String>>(
+        mut self,
+        output_module_hash: H,
+    ) -> Result<Self, Error> {
+        let output_module_hash = output_module_hash.into();
+
+        let recorded: Option<Option<String>> = self
+            .connection
+            .query_row(
+                "SELECT module_hash FROM cursors WHERE id = ?1",
+                params![self.key.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .context(format!("load module hash for {}", self.key))?;
+        if let Some(recorded) = recorded {
+            check_module_hash(&self.key, recorded.as_deref(), &output_module_hash)?;
+        }
+
+        self.module_hash = Some(output_module_hash);
+        Ok(self)
+    }
+}
+
+impl Sink for SqliteSink {
+    fn handle_block(&mut self, data: &BlockScopedData) -> Result<(), Error> {
+        let clock = data
+            .clock
+            .as_ref()
+            .ok_or_else(|| format_err!("received block scoped data without clock"))?;
+        let output = data
+            .output
+            .as_ref()
+            .ok_or_else(|| format_err!("block #{} has no output", clock.number))?;
+
+        if let Some(map_output) = output.map_output.as_ref() {
+            self.pending.push(OutputRow {
+                module: output.name.clone(),
+                block_num: clock.number,
+                block_id: clock.id.clone(),
+                timestamp: clock
+                    .timestamp
+                    .as_ref()
+                    .map(|t| t.seconds)
+                    .unwrap_or_default(),
+                type_url: map_output.type_url.clone(),
+                payload: map_output.value.clone(),
+            });
+        }
+
+        self.pending_cursor = Some(PendingCursor {
+            cursor: data.cursor.clone(),
+            block_num: clock.number,
+            block_id: clock.id.clone(),
+        });
+        self.pending_blocks += 1;
+
+        if self.pending_blocks >= self.batch_size {
+            self.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn undo(&mut self, last_valid_block: &BlockRef) -> Result<(), Error> {
+        // Pending blocks are committed first so that the undo below applies to them too
+        self.flush()?;
+
+        let tx = self
+            .connection
+            .transaction()
+            .context("begin SQLite transaction")?;
+        delete_outputs_above(&tx, &self.key, last_valid_block)?;
+        tx.commit().context("commit SQLite transaction")
+    }
+
+    fn handle_undo(&mut self, signal: &BlockUndoSignal) -> Result<(), Error> {
+        let last_valid_block = signal
+            .last_valid_block
+            .as_ref()
+            .ok_or_else(|| format_err!("received block undo signal without last valid block"))?;
+
+        self.flush()?;
+
+        // Outputs deletion and cursor are committed together, like blocks are
+        let tx = self
+            .connection
+            .transaction()
+            .context("begin SQLite transaction")?;
+        delete_outputs_above(&tx, &self.key, last_valid_block)?;
+        write_cursor(
+            &tx,
+            &self.key,
+            &signal.last_valid_cursor,
+            last_valid_block.number,
+            &last_valid_block.id,
+            self.module_hash.as_deref(),
+        )?;
+        tx.commit().context("commit SQLite transaction")
+    }
+
+    /// Commits every pending output along with the latest cursor in a single transaction.
+    fn flush(&mut self) -> Result<(), Error> {
+        let Some(cursor) = self.pending_cursor.as_ref() else {
+            return Ok(());
+        };
+
+        let tx = self
+            .connection
+            .transaction()
+            .context("begin SQLite transaction")?;
+
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT OR REPLACE INTO outputs (cursor_id, module, block_num, block_id, timestamp, type_url, payload)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+
+            let cursor_id = self.key.to_string();
+            for row in &self.pending {
+                insert
+                    .execute(params![
+                        cursor_id,
+                        row.module,
+                        row.block_num as i64,
+                        row.block_id,
+                        row.timestamp,
+                        row.type_url,
+                        row.payload,
+                    ])
+                    .context(format!("insert output of block #{}", row.block_num))?;
+            }
+        }
+
+        write_cursor(
+            &tx,
+            &self.key,
+            &cursor.cursor,
+            cursor.block_num,
+            &cursor.block_id,
+            self.module_hash.as_deref(),
+        )?;
+        tx.commit().context("commit SQLite transaction")?;
+
+        self.pending.clear();
+        self.pending_blocks = 0;
+        self.pending_cursor = None;
+
+        Ok(())
+    }
+
+    fn cursor(&self) -> Result<Option<String>, Error> {
+        self.load(&self.key)
+    }
+}
+
+impl CursorStore for SqliteSink {
+    fn load(&self, key: &CursorKey) -> Result<Option<String>, Error> {
+        load_cursor(&self.connection, key)
+    }
+
+    fn save(&self, key: &CursorKey, cursor: &str) -> Result<(), Error> {
+        // Saved without any accompanying output (undo signals for example), block
+        // information is kept from the previously saved cursor if any.
+        self.connection
+            .execute(
+                "INSERT INTO cursors (id, cursor, block_num, block_id, module_hash) VALUES (?1, ?2, 0, '', ?3)
+                 ON CONFLICT (id) DO UPDATE SET cursor = excluded.cursor, module_hash = excluded.module_hash",
+                params![key.to_string(), cursor, self.module_hash],
+            )
+            .context(format!("save cursor for {}", key))?;
+
+        Ok(())
+    }
+
+    fn delete(&self, key: &CursorKey) -> Result<(), Error> {
+        // Outputs are kept, they are replaced as blocks are streamed again
+        self.connection
+            .execute(
+                "DELETE FROM cursors WHERE id = ?1",
+                params![key.to_string()],
+            )
+            .context(format!("delete cursor for {}", key))?;
+
+        Ok(())
+    }
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool, Error> {
+    connection
+        .query_row(
+            "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+            params![table, column],
+            |row| row.get(0),
+        )
+        .context("read SQLite schema")
+}
+
+fn load_cursor(connection: &Connection, key: &CursorKey) -> Result<Option<String>, Error> {
+    connection
+        .query_row(
+            "SELECT cursor FROM cursors WHERE id = ?1",
+            params![key.to_string()],
+            |row| row.get(0),
+        )
+        .optional()
+        .context(format!("load cursor for {}", key))
+}
+
+fn delete_outputs_above(
+    connection: &Connection,
+    key: &CursorKey,
+    last_valid_block: &BlockRef,
+) -> Result<(), Error> {
+    let deleted = connection
+        .execute(
+            "DELETE FROM outputs WHERE cursor_id = ?1 AND block_num > ?2",
+            params![key.to_string(), last_valid_block.number as i64],
+        )
+        .context(format!(
+            "delete outputs above block #{}",
+            last_valid_block.number
+        ))?;
+
+    tracing::info!(
+        deleted,
+        number = last_valid_block.number,
+        id = %last_valid_block.id,
+        "Deleted outputs above last valid block"
+    );
+
+    Ok(())
+}
+
+fn write_cursor(
+    connection: &Connection,
+    key: &CursorKey,
+    cursor: &str,
+    block_num: u64,
+    block_id: &str,
+    module_hash: Option<&str>,
+) -> Result<(), Error> {
+    connection
+        .execute(
+            "INSERT OR REPLACE INTO cursors (id, cursor, block_num, block_id, module_hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![key.to_string(), cursor, block_num as i64, block_id, module_hash],
+        )
+        .context(format!("save cursor for {}", key))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{block, forked_chain, replay, undo};
+    use super::*;
+    use crate::substreams_stream::BlockResponse;
+
+    fn sink(batch_size: usize) -> SqliteSink {
+        SqliteSink::new(
+            Connection::open_in_memory().unwrap(),
+            CursorKey::new("http://localhost:9000", "test.spkg", "map_test"),
+            batch_size,
+        )
+        .unwrap()
+    }
+
+    fn block_ids(sink: &SqliteSink) -> Vec<String> {
+        let mut statement = sink
+            .connection
+            .prepare("SELECT block_id FROM outputs WHERE cursor_id = ?1 ORDER BY block_num")
+            .unwrap();
+
+        statement
+            .query_map([sink.key.to_string()], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn handle(sink: &mut SqliteSink, number: u64, id: &str) {
+        let BlockResponse::New(data) = block(number, id, 0) else {
+            unreachable!()
+        };
+        sink.handle_block(&data).unwrap();
+    }
+
+    #[test]
+    fn flush_commits_pending_blocks_with_cursor() {
+        let mut sink = sink(3);
+        handle(&mut sink, 1, "1a");
+        handle(&mut sink, 2, "2a");
+
+        assert!(block_ids(&sink).is_empty());
+        assert_eq!(sink.cursor().unwrap(), None);
+
+        // The batch is full, committed along with the cursor of its last block
+        handle(&mut sink, 3, "3a");
+        assert_eq!(block_ids(&sink), vec!["1a", "2a", "3a"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-3a".to_string()));
+
+        handle(&mut sink, 4, "4a");
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-3a".to_string()));
+        sink.flush().unwrap();
+        assert_eq!(block_ids(&sink), vec!["1a", "2a", "3a", "4a"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-4a".to_string()));
+    }
+
+    #[test]
+    fn uncommitted_blocks_are_lost_with_their_cursor() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.db");
+        let key = CursorKey::new("http://localhost:9000", "test.spkg", "map_test");
+
+        let mut sink = SqliteSink::open(&path, key.clone(), 10).unwrap();
+        handle(&mut sink, 1, "1a");
+        handle(&mut sink, 2, "2a");
+        sink.flush().unwrap();
+        handle(&mut sink, 3, "3a");
+        handle(&mut sink, 4, "4a");
+        // Crash before the batch is committed
+        drop(sink);
+
+        let sink = SqliteSink::open(&path, key, 10).unwrap();
+        assert_eq!(block_ids(&sink), vec!["1a", "2a"]);
+        assert_eq!(sink.cursor().unwrap(), Some("cursor-2a".to_string()));
+    }
+
+    #[test]
+    fn read_cursor_leaves_database_untouched() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.db");
+        let key = CursorKey::new("http://localhost:9000", "test.spkg", "map_test");
+
+        assert!(SqliteSink::read_cursor(&path, &key).is_err());
+        assert!(!path.exists());
+
+        let mut sink = SqliteSink::open(&path, key.clone(), 1).unwrap();
+        handle(&mut sink, 1, "1a");
+        drop(sink);
+
+        assert_eq!(
+            SqliteSink::read_cursor(&path, &key).unwrap(),
+            Some("cursor-1a".to_string())
+        );
+    }
+
+    #[test]
+    fn undo_deletes_outputs_above_last_valid_block() {
+        let mut sink = sink(1);
+        replay(&mut sink, forked_chain()).unwrap();
+
+        assert_eq!(block_ids(&sink), vec!["1a", "2a", "3a", "4b", "5b"]);
+        assert_eq!(sink.load(&sink.key).unwrap(), Some("cursor-5b".to_string()));
+    }
+
+    #[test]
+    fn undo_applies_to_pending_batch() {
+        let mut sink = sink(100);
+        replay(&mut sink, forked_chain()).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(block_ids(&sink), vec!["1a", "2a", "3a", "4b", "5b"]);
+    }
+
+    #[test]
+    fn undo_is_idempotent() {
+        let mut sink = sink(1);
+        replay(&mut sink, forked_chain()).unwrap();
+        replay(&mut sink, vec![undo(3, "3a"), undo(3, "3a")]).unwrap();
+        replay(&mut sink, vec![block(4, "4c", 0)]).unwrap();
+
+        assert_eq!(block_ids(&sink), vec!["1a", "2a", "3a", "4c"]);
+    }
+
+    #[test]
+    fn module_hash_is_committed_with_cursor() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.db");
+        let key = CursorKey::new("http://localhost:9000", "test.spkg", "map_test");
+        let open = |hash: &str| {
+            SqliteSink::open(&path, key.clone(), 1)
+                .unwrap()
+                .with_module_hash(hash)
+        };
+
+        let mut sink = open("hash-1").unwrap();
+        handle(&mut sink, 1, "1a");
+        drop(sink);
+
+        assert!(open("hash-1").is_ok());
+        let err = open("hash-2").err().expect("changed hash is rejected");
+        assert!(format!("{:#}", err).contains("changed from hash-1 to hash-2"));
+
+        // A cursor committed without module hash cannot be trusted either
+        let mut sink = SqliteSink::open(&path, key.clone(), 1).unwrap();
+        handle(&mut sink, 2, "2a");
+        drop(sink);
+        let err = open("hash-1").err().expect("missing hash is rejected");
+        assert!(format!("{:#}", err).contains("no output module hash"));
+
+        SqliteSink::open(&path, key.clone(), 1)
+            .unwrap()
+            .delete(&key)
+            .unwrap();
+        assert!(open("hash-2").is_ok());
+    }
+
+    #[test]
+    fn outputs_are_kept_apart_per_endpoint_and_package() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("outputs.db");
+        let mainnet = CursorKey::new("http://mainnet:9000", "test.spkg", "map_test");
+        let testnet = CursorKey::new("http://testnet:9000", "test.spkg", "map_test");
+
+        let mut mainnet = SqliteSink::open(&path, mainnet, 1).unwrap();
+        let mut testnet = SqliteSink::open(&path, testnet, 1).unwrap();
+        handle(&mut mainnet, 1, "1a");
+        handle(&mut mainnet, 2, "2a");
+        handle(&mut testnet, 1, "1a");
+        handle(&mut testnet, 2, "2a");
+
+        replay(&mut testnet, vec![undo(1, "1a")]).unwrap();
+
+        assert_eq!(block_ids(&mainnet), vec!["1a", "2a"]);
+        assert_eq!(block_ids(&testnet), vec!["1a"]);
+    }
+
+    #[test]
+    fn open_rejects_outputs_not_keyed_by_cursor() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE outputs (
+                    module TEXT NOT NULL,
+                    block_num INTEGER NOT NULL,
+                    block_id TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL,
+                    type_url TEXT NOT NULL,
+                    payload BLOB NOT NULL,
+                    PRIMARY KEY (module, block_num, block_id)
+                );",
+            )
+            .unwrap();
+
+        let err = SqliteSink::new(
+            connection,
+            CursorKey::new("http://localhost:9000", "test.spkg", "map_test"),
+            1,
+        )
+        .err()
+        .expect("legacy schema is rejected");
+        assert!(format!("{:#}", err).contains("previous version"));
+    }
+}
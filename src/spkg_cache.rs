@@ -0,0 +1,182 @@
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{format_err, Context, Error};
+use sha2::{Digest, Sha256};
+
+use crate::cursor::write_atomically;
+
+/// On-disk cache of downloaded `.spkg` files.
+///
+/// Packages are stored once per content under `blobs/<sha256>.spkg`, and each URL
+/// points to the content it last served through `refs/<sha256 of url>`, along
+/// with the time it was fetched. Files are written atomically so that concurrent
+/// processes sharing the cache never read a partially written package.
+#[derive(Clone, Debug)]
+pub struct SpkgCache {
+    directory: PathBuf,
+}
+
+impl SpkgCache {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        SpkgCache {
+            directory: directory.into(),
+        }
+    }
+
+    /// `$XDG_CACHE_HOME/substreams/spkg`, or `~/.cache/substreams/spkg`.
+    pub fn default_directory() -> Option<PathBuf> {
+        let cache_home = env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+
+        Some(cache_home.join("substreams").join("spkg"))
+    }
+
+    /// Returns the package last fetched from `url`, unless it was fetched more than
+    /// `max_age` ago. Use `None` for contents that never change (pinned versions).
+    pub fn get(&self, url: &str, max_age: Option<Duration>) -> Result<Option<Vec<u8>>, Error> {
+        let ref_path = self.ref_path(url);
+        let reference = match fs::read_to_string(&ref_path) {
+            Ok(reference) => reference,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("read cache entry '{}'", ref_path.display())),
+        };
+
+        let mut lines = reference.lines();
+        let (Some(digest), Some(fetched_at)) = (lines.next(), lines.next()) else {
+            return Ok(None);
+        };
+        let fetched_at = UNIX_EPOCH + Duration::from_secs(fetched_at.parse().unwrap_or(0));
+
+        if let Some(max_age) = max_age {
+            let age = SystemTime::now()
+                .duration_since(fetched_at)
+                .unwrap_or_default();
+            if age > max_age {
+                return Ok(None);
+            }
+        }
+
+        let blob_path = self.blob_path(digest);
+        let content = match fs::read(&blob_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("read cached package '{}'", blob_path.display()))
+            }
+        };
+
+        // A corrupted blob is treated like a missing one, it is replaced on next put
+        if sha256_hex(&content) != digest {
+            return Ok(None);
+        }
+
+        Ok(Some(content))
+    }
+
+    /// Stores `content` as the package served by `url`, fetched now.
+    pub fn put(&self, url: &str, content: &[u8]) -> Result<(), Error> {
+        let digest = sha256_hex(content);
+        let blob_path = self.blob_path(&digest);
+        let stored = fs::read(&blob_path).map_or(false, |stored| sha256_hex(&stored) == digest);
+        if !stored {
+            create_parent(&blob_path)?;
+            write_atomically(&blob_path, content)?;
+        }
+
+        let fetched_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| format_err!("system clock is before UNIX epoch: {}", e))?;
+
+        let ref_path = self.ref_path(url);
+        create_parent(&ref_path)?;
+        write_atomically(
+            &ref_path,
+            format!("{}\n{}\n{}\n", digest, fetched_at.as_secs(), url).as_bytes(),
+        )
+    }
+
+    fn ref_path(&self, url: &str) -> PathBuf {
+        self.directory.join("refs").join(sha256_hex(url.as_bytes()))
+    }
+
+    fn blob_path(&self, digest: &str) -> PathBuf {
+        self.directory
+            .join("blobs")
+            .join(format!("{}.spkg", digest))
+    }
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .fold(String::with_capacity(64), |mut output, byte| {
+            write!(output, "{:02x}", byte).expect("writing to a String never fails");
+            output
+        })
+}
+
+fn create_parent(path: &Path) -> Result<(), Error> {
+    let Some(parent) = path.parent() else {
+        return Ok(());
+    };
+
+    fs::create_dir_all(parent).context(format!("create cache directory '{}'", parent.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://spkg.io/v1/packages/ethereum-explorer/latest";
+
+    #[test]
+    fn caches_packages_by_url() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = SpkgCache::new(directory.path());
+
+        assert_eq!(cache.get(URL, None).unwrap(), None);
+
+        cache.put(URL, b"v1").unwrap();
+        assert_eq!(cache.get(URL, None).unwrap(), Some(b"v1".to_vec()));
+        assert_eq!(
+            cache.get(URL, Some(Duration::from_secs(60))).unwrap(),
+            Some(b"v1".to_vec())
+        );
+
+        cache.put(URL, b"v2").unwrap();
+        assert_eq!(cache.get(URL, None).unwrap(), Some(b"v2".to_vec()));
+        assert_eq!(
+            cache.get("https://example.com/other.spkg", None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn expired_and_corrupted_entries_are_ignored() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = SpkgCache::new(directory.path());
+        cache.put(URL, b"v1").unwrap();
+
+        // Backdate the entry by an hour
+        let ref_path = cache.ref_path(URL);
+        let reference = fs::read_to_string(&ref_path).unwrap();
+        let mut lines: Vec<String> = reference.lines().map(String::from).collect();
+        lines[1] = (lines[1].parse::<u64>().unwrap() - 3600).to_string();
+        fs::write(&ref_path, lines.join("\n")).unwrap();
+
+        assert_eq!(cache.get(URL, Some(Duration::from_secs(60))).unwrap(), None);
+        assert_eq!(cache.get(URL, None).unwrap(), Some(b"v1".to_vec()));
+
+        fs::write(cache.blob_path(&sha256_hex(b"v1")), b"corrupted").unwrap();
+        assert_eq!(cache.get(URL, None).unwrap(), None);
+    }
+}
@@ -1,14 +1,21 @@
 use std::{fmt::Display, sync::Arc, time::Duration};
 
+use anyhow::{format_err, Error};
 use http::{uri::Scheme, Uri};
 use tonic::{
     codec::CompressionEncoding,
     codegen::http,
     metadata::MetadataValue,
+    service::Interceptor,
     transport::{Channel, ClientTlsConfig},
 };
 
-use crate::pb::sf::substreams::rpc::v2::{stream_client::StreamClient, Request, Response};
+use crate::pb::sf::{
+    firehose::v2::{InfoRequest, InfoResponse},
+    substreams::rpc::v2::{
+        endpoint_info_client::EndpointInfoClient, stream_client::StreamClient, Request, Response,
+    },
+};
 
 #[derive(Clone, Debug)]
 pub struct SubstreamsEndpoint {
@@ -54,29 +61,117 @@ impl SubstreamsEndpoint {
         self: Arc<Self>,
         request: Request,
     ) -> Result<tonic::Streaming<Response>, anyhow::Error> {
+        let mut client =
+            StreamClient::with_interceptor(self.channel.clone(), self.authorization()?)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Gzip)
+                .max_decoding_message_size(10 * 1024 * 1024);
+
+        let response_stream = client.blocks(request).await?;
+        let block_stream = response_stream.into_inner();
+
+        Ok(block_stream)
+    }
+
+    /// Queries the `EndpointInfo/Info` RPC, describing the chain served by the endpoint
+    /// and its first streamable block. Failed calls are `tonic::Status` errors.
+    pub async fn info(&self) -> Result<InfoResponse, anyhow::Error> {
+        let mut client =
+            EndpointInfoClient::with_interceptor(self.channel.clone(), self.authorization()?);
+
+        Ok(client.info(InfoRequest {}).await?.into_inner())
+    }
+
+    fn authorization(&self) -> Result<impl Interceptor, anyhow::Error> {
         let token_metadata: Option<MetadataValue<tonic::metadata::Ascii>> = match self.token.clone()
         {
             Some(token) => Some(token.as_str().try_into()?),
             None => None,
         };
 
-        let mut client = StreamClient::with_interceptor(
-            self.channel.clone(),
-            move |mut r: tonic::Request<()>| {
-                if let Some(ref t) = token_metadata {
-                    r.metadata_mut().insert("authorization", t.clone());
-                }
+        Ok(move |mut r: tonic::Request<()>| {
+            if let Some(ref t) = token_metadata {
+                r.metadata_mut().insert("authorization", t.clone());
+            }
+
+            Ok(r)
+        })
+    }
+}
+
+/// Fails unless the chain served by the endpoint, as described by its `Info`
+/// response, is the package's `network` (its canonical name or one of its aliases).
+pub fn check_network(info: &InfoResponse, network: &str) -> Result<(), Error> {
+    let matches = std::iter::once(&info.chain_name)
+        .chain(info.chain_name_aliases.iter())
+        .any(|name| name.eq_ignore_ascii_case(network));
 
-                Ok(r)
+    if !matches {
+        return Err(format_err!(
+            "endpoint serves chain '{}' (aliases: {}) but the package network is '{}'",
+            info.chain_name,
+            match info.chain_name_aliases.is_empty() {
+                true => "none".to_string(),
+                false => info.chain_name_aliases.join(", "),
             },
-        )
-        .accept_compressed(CompressionEncoding::Gzip)
-        .send_compressed(CompressionEncoding::Gzip)
-        .max_decoding_message_size(10 * 1024 * 1024);
+            network
+        ));
+    }
 
-        let response_stream = client.blocks(request).await?;
-        let block_stream = response_stream.into_inner();
+    Ok(())
+}
 
-        Ok(block_stream)
+/// Returns the start block to request given the endpoint's first streamable block.
+/// Start blocks below it are clamped to it when `clamp` is set, rejected otherwise.
+/// Negative start blocks (relative to the chain head) are returned as is.
+pub fn check_start_block(info: &InfoResponse, start_block: i64, clamp: bool) -> Result<i64, Error> {
+    let first = info.first_streamable_block_num;
+    if start_block < 0 || start_block as u64 >= first {
+        return Ok(start_block);
+    }
+
+    if !clamp {
+        return Err(format_err!(
+            "start block #{} is below the first block #{} served by the endpoint",
+            start_block,
+            first
+        ));
+    }
+
+    Ok(first as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> InfoResponse {
+        InfoResponse {
+            chain_name: "mainnet".to_string(),
+            chain_name_aliases: vec!["ethereum".to_string(), "eth-mainnet".to_string()],
+            first_streamable_block_num: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn network_must_match_chain() {
+        check_network(&info(), "mainnet").unwrap();
+        check_network(&info(), "Ethereum").unwrap();
+
+        let err = check_network(&info(), "sepolia").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "endpoint serves chain 'mainnet' (aliases: ethereum, eth-mainnet) but the package network is 'sepolia'"
+        );
+    }
+
+    #[test]
+    fn start_block_below_first_streamable_block() {
+        assert_eq!(check_start_block(&info(), 150, false).unwrap(), 150);
+        assert_eq!(check_start_block(&info(), 100, false).unwrap(), 100);
+        assert_eq!(check_start_block(&info(), -10, false).unwrap(), -10);
+        assert_eq!(check_start_block(&info(), 0, true).unwrap(), 100);
+        assert!(check_start_block(&info(), 99, false).is_err());
     }
 }
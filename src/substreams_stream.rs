@@ -1,25 +1,38 @@
 use anyhow::{anyhow, Error};
 use async_stream::try_stream;
 use futures03::{Stream, StreamExt};
+use prost::Message as _;
 use std::{
+    future::Future,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
     time::{Duration, Instant},
 };
 use tokio::time::sleep;
-use tokio_retry::strategy::ExponentialBackoff;
+use tokio_retry::strategy;
+use tracing::{debug, info, info_span, warn, Span};
 
+use crate::cursor::redact_cursor;
+use crate::error::{retry_after, ErrorClass, ErrorClassifier, SubstreamsError};
+use crate::metrics::Metrics;
+use crate::package::prune_modules;
 use crate::pb::sf::substreams::rpc::v2::{
-    response::Message, BlockScopedData, BlockUndoSignal, Request, Response,
+    self, response::Message, BlockScopedData, BlockUndoSignal, ModulesProgress, Request, Response,
+    SessionInit,
 };
 use crate::pb::sf::substreams::v1::Modules;
+use crate::progress::Progress;
 
 use crate::substreams::SubstreamsEndpoint;
 
 pub enum BlockResponse {
     New(BlockScopedData),
     Undo(BlockUndoSignal),
+    /// Received first on every (re)connection, before any block
+    Session(SessionInit),
+    /// Parallel processing progress, received until the linear handoff block
+    Progress(Progress),
 }
 
 pub struct SubstreamsStream {
@@ -27,6 +40,8 @@ pub struct SubstreamsStream {
 }
 
 impl SubstreamsStream {
+    /// Shortcut for the most common [SubstreamsStream::builder] configuration, fails
+    /// like [SubstreamsStreamBuilder::build] does (unknown output module for example).
     pub fn new(
         endpoint: Arc<SubstreamsEndpoint>,
         cursor: Option<String>,
@@ -34,68 +49,312 @@ impl SubstreamsStream {
         output_module_name: String,
         start_block: i64,
         end_block: u64,
-    ) -> Self {
-        SubstreamsStream {
-            stream: Box::pin(stream_blocks(
-                endpoint,
-                cursor,
-                modules,
-                output_module_name,
-                start_block,
-                end_block,
-            )),
+    ) -> Result<Self, Error> {
+        Self::builder()
+            .endpoint(endpoint)
+            .cursor(cursor)
+            .modules(modules)
+            .output_module(output_module_name)
+            .start_block(start_block)
+            .stop_block(end_block)
+            .build()
+    }
+
+    pub fn builder() -> SubstreamsStreamBuilder {
+        SubstreamsStreamBuilder::default()
+    }
+}
+
+/// Delays applied between reconnection attempts. The delay starts at `initial_delay`
+/// and doubles after each failed attempt up to `max_delay`, it is reset as soon as
+/// a block is received.
+#[derive(Clone, Debug)]
+pub struct BackoffPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Number of consecutive failed attempts after which the stream gives up, `None`
+    /// retries forever.
+    pub max_retries: Option<usize>,
+    /// Randomizes each delay between zero and its computed value, avoids many
+    /// clients reconnecting in lockstep after a server restart.
+    pub jitter: bool,
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        BackoffPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(45),
+            max_retries: None,
+            jitter: false,
         }
     }
 }
 
-// Create the Stream implementation that streams blocks with auto-reconnection.
-fn stream_blocks(
-    endpoint: Arc<SubstreamsEndpoint>,
-    cursor: Option<String>,
-    modules: Option<Modules>,
-    output_module_name: String,
-    start_block_num: i64,
-    stop_block_num: u64,
-) -> impl Stream<Item = Result<BlockResponse, Error>> {
-    let mut latest_cursor = cursor.unwrap_or_else(|| "".to_string());
-    let mut backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
-    let mut last_progress_report = Instant::now();
+impl BackoffPolicy {
+    fn delays(&self) -> Box<dyn Iterator<Item = Duration> + Send> {
+        let max_delay = self.max_delay;
+        let jitter = self.jitter;
 
-    try_stream! {
-        loop {
-            println!("Blockstreams disconnected, connecting (endpoint {}, start block {}, stop block {}, cursor {})",
-                &endpoint,
-                start_block_num,
-                stop_block_num,
-                &latest_cursor
-            );
+        let delays = std::iter::successors(Some(self.initial_delay.min(max_delay)), move |d| {
+            Some(d.saturating_mul(2).min(max_delay))
+        })
+        .map(move |d| if jitter { strategy::jitter(d) } else { d });
 
-            let result = endpoint.clone().substreams(Request {
-                start_block_num,
-                start_cursor: latest_cursor.clone(),
-                stop_block_num,
-                final_blocks_only: false,
-                modules: modules.clone(),
-                output_module: output_module_name.clone(),
+        match self.max_retries {
+            Some(max_retries) => Box::new(delays.take(max_retries)),
+            None => Box::new(delays),
+        }
+    }
+}
+
+/// Configures a [SubstreamsStream], every field of the underlying `Request` can be
+/// set along with the reconnection behavior. `endpoint` and `output_module` are
+/// mandatory.
+#[derive(Clone)]
+pub struct SubstreamsStreamBuilder {
+    endpoint: Option<Arc<SubstreamsEndpoint>>,
+    request: Request,
+    backoff: BackoffPolicy,
+    progress_report_interval: Duration,
+    error_classifier: ErrorClassifier,
+    prune_modules: bool,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for SubstreamsStreamBuilder {
+    fn default() -> Self {
+        SubstreamsStreamBuilder {
+            endpoint: None,
+            request: Request {
                 // There is usually no good reason for you to consume the stream development mode (so switching `true`
                 // to `false`). If you do switch it, be aware that more than one output module will be send back to you,
                 // and the current code in `process_block_scoped_data` (within your 'main.rs' file) expects a single
                 // module.
                 production_mode: true,
-                debug_initial_store_snapshot_for_modules: vec![],
-                noop_mode: false,
+                ..Default::default()
+            },
+            backoff: BackoffPolicy::default(),
+            progress_report_interval: Duration::from_secs(30),
+            error_classifier: ErrorClassifier::default(),
+            prune_modules: true,
+            metrics: None,
+        }
+    }
+}
+
+impl SubstreamsStreamBuilder {
+    pub fn endpoint(mut self, endpoint: Arc<SubstreamsEndpoint>) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    pub fn modules(mut self, modules: Option<Modules>) -> Self {
+        self.request.modules = modules;
+        self
+    }
+
+    pub fn output_module<S: Into<String>>(mut self, output_module: S) -> Self {
+        self.request.output_module = output_module.into();
+        self
+    }
+
+    /// Cursor to resume from, when set `start_block` is ignored by the server.
+    pub fn cursor(mut self, cursor: Option<String>) -> Self {
+        self.request.start_cursor = cursor.unwrap_or_default();
+        self
+    }
+
+    /// Negative values are relative to the chain's head block.
+    pub fn start_block(mut self, start_block: i64) -> Self {
+        self.request.start_block_num = start_block;
+        self
+    }
+
+    /// Exclusive stop block, `0` streams forever.
+    pub fn stop_block(mut self, stop_block: u64) -> Self {
+        self.request.stop_block_num = stop_block;
+        self
+    }
+
+    pub fn final_blocks_only(mut self, final_blocks_only: bool) -> Self {
+        self.request.final_blocks_only = final_blocks_only;
+        self
+    }
+
+    pub fn production_mode(mut self, production_mode: bool) -> Self {
+        self.request.production_mode = production_mode;
+        self
+    }
+
+    pub fn noop_mode(mut self, noop_mode: bool) -> Self {
+        self.request.noop_mode = noop_mode;
+        self
+    }
+
+    /// Only available in development mode.
+    pub fn debug_initial_store_snapshot_for_modules(mut self, modules: Vec<String>) -> Self {
+        self.request.debug_initial_store_snapshot_for_modules = modules;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: BackoffPolicy) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn initial_backoff(mut self, delay: Duration) -> Self {
+        self.backoff.initial_delay = delay;
+        self
+    }
+
+    pub fn max_backoff(mut self, delay: Duration) -> Self {
+        self.backoff.max_delay = delay;
+        self
+    }
+
+    pub fn max_retries(mut self, max_retries: Option<usize>) -> Self {
+        self.backoff.max_retries = max_retries;
+        self
+    }
+
+    pub fn backoff_jitter(mut self, jitter: bool) -> Self {
+        self.backoff.jitter = jitter;
+        self
+    }
+
+    /// Minimum interval between two [BlockResponse::Progress], progress messages received
+    /// in between are not forwarded (they are still recorded by the metrics).
+    pub fn progress_report_interval(mut self, interval: Duration) -> Self {
+        self.progress_report_interval = interval;
+        self
+    }
+
+    /// Decides which errors are retried, which are rate limits and which end the stream.
+    pub fn error_classifier(mut self, error_classifier: ErrorClassifier) -> Self {
+        self.error_classifier = error_classifier;
+        self
+    }
+
+    /// Only sends the modules and binaries needed to compute the output module (and
+    /// the debug snapshot modules), enabled by default. Large packages make for much
+    /// smaller requests.
+    pub fn prune_modules(mut self, prune_modules: bool) -> Self {
+        self.prune_modules = prune_modules;
+        self
+    }
+
+    /// Records the stream's health and throughput, see [Metrics].
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn build(mut self) -> Result<SubstreamsStream, Error> {
+        let endpoint = self
+            .endpoint
+            .ok_or_else(|| anyhow!("an endpoint is required to build a SubstreamsStream"))?;
+
+        if self.request.output_module.is_empty() {
+            return Err(anyhow!(
+                "an output module is required to build a SubstreamsStream"
+            ));
+        }
+
+        if let (true, Some(modules)) = (self.prune_modules, &self.request.modules) {
+            let mut needed = vec![self.request.output_module.as_str()];
+            needed.extend(
+                self.request
+                    .debug_initial_store_snapshot_for_modules
+                    .iter()
+                    .map(String::as_str),
+            );
+
+            self.request.modules = Some(prune_modules(modules, &needed)?);
+        }
+
+        Ok(SubstreamsStream {
+            stream: Box::pin(stream_blocks(
+                endpoint.to_string(),
+                move |request| endpoint.clone().substreams(request),
+                self.request,
+                self.backoff,
+                self.progress_report_interval,
+                self.error_classifier,
+                self.metrics,
+            )),
+        })
+    }
+}
+
+// Create the Stream implementation that streams blocks with auto-reconnection, `connect`
+// opens the server's response stream for each connection attempt.
+fn stream_blocks<C, F, R>(
+    endpoint: String,
+    connect: C,
+    request: Request,
+    backoff_policy: BackoffPolicy,
+    progress_report_interval: Duration,
+    error_classifier: ErrorClassifier,
+    metrics: Option<Arc<Metrics>>,
+) -> impl Stream<Item = Result<BlockResponse, Error>>
+where
+    C: Fn(Request) -> F,
+    F: Future<Output = Result<R, Error>>,
+    R: Stream<Item = Result<Response, tonic::Status>>,
+{
+    let mut latest_cursor = request.start_cursor.clone();
+    let mut backoff = backoff_policy.delays();
+    let mut last_progress_report: Option<Instant> = None;
+    // Trace ID of the last session, attached to errors for support requests
+    let mut trace_id: Option<String> = None;
+
+    try_stream! {
+        loop {
+            // Events are attached to the span explicitly, entering it would leak it to
+            // whatever else runs on the thread while the stream is suspended
+            let span = info_span!(
+                "connection",
+                endpoint = %endpoint,
+                cursor = %redact_cursor(&latest_cursor),
+                start_block = request.start_block_num,
+                stop_block = request.stop_block_num,
+                trace_id = tracing::field::Empty,
+            );
+            info!(parent: &span, "Connecting");
+
+            let result = connect(Request {
+                start_cursor: latest_cursor.clone(),
+                ..request.clone()
             }).await;
 
+            // Delay requested by the server before retrying, when rate limited
+            let mut retry_after_hint: Option<Duration> = None;
+            let last_error: String;
+            let last_code: tonic::Code;
+
             match result {
                 Ok(stream) => {
-                    println!("Blockstreams connected");
+                    info!(parent: &span, "Connected");
 
-                    let mut encountered_error = false;
+                    let mut encountered_error: Option<tonic::Status> = None;
                     for await response in stream{
-                        match process_substreams_response(response, &mut last_progress_report).await {
+                        if let (Some(metrics), Ok(response)) = (&metrics, &response) {
+                            metrics.record_received_bytes(response.encoded_len());
+                        }
+
+                        match process_substreams_response(response) {
                             BlockProcessedResult::BlockScopedData(block_scoped_data) => {
                                 // Reset backoff because we got a good value from the stream
-                                backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                backoff = backoff_policy.delays();
+                                if let Some(metrics) = &metrics {
+                                    metrics.record_connected();
+                                    metrics.record_block(&block_scoped_data);
+                                }
+
+                                if let Some(clock) = &block_scoped_data.clock {
+                                    debug!(parent: &span, number = clock.number, id = %clock.id, "Block received");
+                                }
 
                                 let cursor = block_scoped_data.cursor.clone();
                                 yield BlockResponse::New(block_scoped_data);
@@ -104,134 +363,171 @@ fn stream_blocks(
                             },
                             BlockProcessedResult::BlockUndoSignal(block_undo_signal) => {
                                 // Reset backoff because we got a good value from the stream
-                                backoff = ExponentialBackoff::from_millis(500).max_delay(Duration::from_secs(45));
+                                backoff = backoff_policy.delays();
+                                if let Some(metrics) = &metrics {
+                                    metrics.record_connected();
+                                    metrics.record_undo(&block_undo_signal);
+                                }
+
+                                if let Some(block) = &block_undo_signal.last_valid_block {
+                                    info!(parent: &span, number = block.number, id = %block.id, "Undo received");
+                                }
 
                                 let cursor = block_undo_signal.last_valid_cursor.clone();
                                 yield BlockResponse::Undo(block_undo_signal);
 
                                 latest_cursor = cursor;
                             },
-                            BlockProcessedResult::Skip() => {},
-                            BlockProcessedResult::TonicError(status) => {
-                                // Unauthenticated errors are not retried, we forward the error back to the
-                                // stream consumer which handles it
-                                if status.code() == tonic::Code::Unauthenticated {
-                                    return Err(anyhow::Error::new(status.clone()))?;
+                            BlockProcessedResult::Session(session) => {
+                                span.record("trace_id", session.trace_id.as_str());
+                                info!(
+                                    parent: &span,
+                                    resolved_start_block = session.resolved_start_block,
+                                    linear_handoff_block = session.linear_handoff_block,
+                                    max_parallel_workers = session.max_parallel_workers,
+                                    "Session initialized"
+                                );
+
+                                trace_id = Some(session.trace_id.clone());
+                                yield BlockResponse::Session(session);
+                            },
+                            BlockProcessedResult::Progress(progress) => {
+                                let progress = Progress::from(progress);
+                                if let Some(metrics) = &metrics {
+                                    metrics.record_progress(&progress);
                                 }
 
-                                println!("Received tonic error {:#}", status);
-                                encountered_error = true;
+                                if last_progress_report.map_or(true, |at| at.elapsed() >= progress_report_interval) {
+                                    last_progress_report = Some(Instant::now());
+                                    yield BlockResponse::Progress(progress);
+                                }
+                            },
+                            BlockProcessedResult::Skip() => {},
+                            BlockProcessedResult::FatalError(error) => {
+                                // The server gave up on the request, retrying would fail the same
+                                // way so we forward the error back to the stream consumer
+                                return Err(with_trace_id(anyhow::Error::new(SubstreamsError::from(error)), &trace_id))?;
+                            },
+                            BlockProcessedResult::TonicError(status) => {
+                                warn!(parent: &span, code = ?status.code(), "Received tonic error: {:#}", status);
+                                encountered_error = Some(status);
                                 break;
                             },
                         }
                     }
 
-                    if !encountered_error {
-                        println!("Stream completed, reached end block");
+                    let Some(status) = encountered_error else {
+                        info!(parent: &span, "Stream completed, reached end block");
                         return
-                    }
+                    };
+
+                    // Non-retryable errors are forwarded back to the stream consumer which handles them
+                    last_error = format!("{:#}", status);
+                    last_code = status.code();
+                    retry_after_hint = classify_status(&error_classifier, status)
+                        .map_err(|e| with_trace_id(e, &trace_id))?;
                 },
                 Err(e) => {
                     // We failed to connect and will try again; this is another
                     // case where we actually _want_ to back off in case we keep
-                    // having connection errors.
+                    // having connection errors, unless the server refused the request.
+                    warn!(parent: &span, "Unable to connect to endpoint: {:#}", e);
 
-                    println!("Unable to connect to endpoint: {:#}", e);
+                    match e.downcast::<tonic::Status>() {
+                        Ok(status) => {
+                            last_error = format!("{:#}", status);
+                            last_code = status.code();
+                            retry_after_hint = classify_status(&error_classifier, status)
+                                .map_err(|e| with_trace_id(e, &trace_id))?;
+                        },
+                        Err(e) => {
+                            last_error = format!("{:#}", e);
+                            last_code = tonic::Code::Unknown;
+                        },
+                    }
                 }
             }
 
             // If we reach this point, we must wait a bit before retrying
-            if let Some(duration) = backoff.next() {
-                sleep(duration).await
-            } else {
-                return Err(anyhow!("backoff requested to stop retrying, quitting"))?;
+            let Some(duration) = backoff.next() else {
+                return Err(with_trace_id(anyhow!(SubstreamsError::RetriesExhausted { last_error }), &trace_id))?;
+            };
+
+            let delay = match retry_after_hint {
+                Some(hint) if hint > duration => {
+                    warn!(parent: &span, delay = ?hint, "Rate limited by the server");
+                    hint
+                },
+                _ => duration,
+            };
+            info!(parent: &span, ?delay, "Disconnected, reconnecting");
+
+            if let Some(metrics) = &metrics {
+                metrics.record_reconnect(last_code, delay);
             }
+            sleep(delay).await
         }
     }
 }
 
+/// Returns the server's retry delay hint when the status is retryable, or the error
+/// to end the stream with otherwise.
+fn classify_status(
+    error_classifier: &ErrorClassifier,
+    status: tonic::Status,
+) -> Result<Option<Duration>, Error> {
+    match error_classifier.classify(&status) {
+        ErrorClass::Fatal => Err(anyhow!(SubstreamsError::NonRetryable(status))),
+        ErrorClass::RateLimited => Ok(retry_after(&status)),
+        ErrorClass::Retryable => Ok(None),
+    }
+}
+
+/// Attaches the trace ID of the last session to `error`, the endpoint's operator needs
+/// it to investigate a failed request.
+fn with_trace_id(error: Error, trace_id: &Option<String>) -> Error {
+    match trace_id {
+        Some(trace_id) => error.context(format!("trace ID {}", trace_id)),
+        None => error,
+    }
+}
+
+/// Span covering the handling of a block, see `run_sink`.
+pub(crate) fn block_span(data: &BlockScopedData) -> Span {
+    match &data.clock {
+        Some(clock) => info_span!("block", number = clock.number, id = %clock.id),
+        None => info_span!("block"),
+    }
+}
+
 enum BlockProcessedResult {
     Skip(),
+    Session(SessionInit),
+    Progress(ModulesProgress),
     BlockScopedData(BlockScopedData),
     BlockUndoSignal(BlockUndoSignal),
+    FatalError(v2::Error),
     TonicError(tonic::Status),
 }
 
-async fn process_substreams_response(
-    result: Result<Response, tonic::Status>,
-    last_progress_report: &mut Instant,
-) -> BlockProcessedResult {
+fn process_substreams_response(result: Result<Response, tonic::Status>) -> BlockProcessedResult {
     let response = match result {
         Ok(v) => v,
         Err(e) => return BlockProcessedResult::TonicError(e),
     };
 
     match response.message {
-        Some(Message::Session(session)) => {
-            println!(
-                "Received session message (Workers {}, Trace ID {})",
-                session.max_parallel_workers, &session.trace_id
-            );
-            BlockProcessedResult::Skip()
-        }
+        Some(Message::Session(session)) => BlockProcessedResult::Session(session),
         Some(Message::BlockScopedData(block_scoped_data)) => {
             BlockProcessedResult::BlockScopedData(block_scoped_data)
         }
         Some(Message::BlockUndoSignal(block_undo_signal)) => {
             BlockProcessedResult::BlockUndoSignal(block_undo_signal)
         }
-        Some(Message::Progress(progress)) => {
-            if last_progress_report.elapsed() > Duration::from_secs(30) {
-                let processed_bytes = progress.processed_bytes.unwrap_or_default();
-
-                println!(
-                    "Latest progress message received (Stages: {}, Jobs: {}, Processed Bytes: [Read: {}, Written: {}])",
-                    progress.stages.len(),
-                    progress.running_jobs.len(),
-                    processed_bytes.total_bytes_read,
-                    processed_bytes.total_bytes_written,
-                );
-                *last_progress_report = Instant::now();
-            }
-
-            // The `ModulesProgress` messages goal is to report active parallel processing happening
-            // either to fill up backward (relative to your request's start block) some missing state
-            // or pre-process forward blocks (again relative).
-            //
-            // You could log that in trace or accumulate to push as metrics. Here a snippet of code
-            // that prints progress to standard out. If your `BlockScopedData` messages seems to never
-            // arrive in production mode, it's because progresses is happening but not yet for the output
-            // module you requested.
-            //
-            // let progresses: Vec<_> = progress
-            //     .modules
-            //     .iter()
-            //     .filter_map(|module| {
-            //         use crate::pb::sf::substreams::rpc::v2::module_progress::Type;
-
-            //         if let Type::ProcessedRanges(range) = module.r#type.as_ref().unwrap() {
-            //             Some(format!(
-            //                 "{} @ [{}]",
-            //                 module.name,
-            //                 range
-            //                     .processed_ranges
-            //                     .iter()
-            //                     .map(|x| x.to_string())
-            //                     .collect::<Vec<_>>()
-            //                     .join(", ")
-            //             ))
-            //         } else {
-            //             None
-            //         }
-            //     })
-            //     .collect();
-
-            // println!("Progess {}", progresses.join(", "));
-
-            BlockProcessedResult::Skip()
-        }
+        Some(Message::FatalError(error)) => BlockProcessedResult::FatalError(error),
+        Some(Message::Progress(progress)) => BlockProcessedResult::Progress(progress),
         None => {
-            println!("Got None on substream message");
+            warn!("Got None on substream message");
             BlockProcessedResult::Skip()
         }
         _ => BlockProcessedResult::Skip(),
@@ -245,3 +541,238 @@ impl Stream for SubstreamsStream {
         self.stream.poll_next_unpin(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        },
+    };
+
+    use futures03::stream;
+
+    use super::*;
+
+    #[test]
+    fn backoff_policy_delays() {
+        let policy = BackoffPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(3),
+            max_retries: Some(5),
+            jitter: false,
+        };
+
+        let delays: Vec<_> = policy.delays().map(|d| d.as_millis()).collect();
+        assert_eq!(delays, vec![500, 1000, 2000, 3000, 3000]);
+    }
+
+    #[test]
+    fn backoff_policy_jitter_stays_below_delay() {
+        let policy = BackoffPolicy {
+            jitter: true,
+            max_retries: Some(10),
+            ..Default::default()
+        };
+
+        let expected = BackoffPolicy {
+            jitter: false,
+            ..policy.clone()
+        };
+
+        for (jittered, delay) in policy.delays().zip(expected.delays()) {
+            assert!(jittered <= delay);
+        }
+    }
+
+    #[test]
+    fn builder_requires_output_module() {
+        assert!(SubstreamsStream::builder().build().is_err());
+    }
+
+    #[tokio::test]
+    async fn new_rejects_unknown_output_module() {
+        let endpoint = Arc::new(
+            SubstreamsEndpoint::new("http://localhost:9000", None)
+                .await
+                .unwrap(),
+        );
+        let modules = Modules {
+            modules: vec![crate::package::tests::map_module("map_events", vec![])],
+            binaries: vec![Default::default()],
+        };
+
+        let result = SubstreamsStream::new(
+            endpoint,
+            None,
+            Some(modules),
+            "map_evnets".to_string(),
+            0,
+            0,
+        );
+        assert!(format!("{:#}", result.err().unwrap()).contains("map_evnets"));
+    }
+
+    #[test]
+    fn fatal_error_response_carries_module_logs() {
+        let response = Response {
+            message: Some(Message::FatalError(v2::Error {
+                module: "map_pools".to_string(),
+                reason: "panicked at 'index out of bounds'".to_string(),
+                logs: vec!["processing pool 0x01".to_string()],
+                logs_truncated: true,
+            })),
+        };
+
+        let BlockProcessedResult::FatalError(error) = process_substreams_response(Ok(response))
+        else {
+            panic!("expected fatal error");
+        };
+
+        let error = anyhow::Error::new(SubstreamsError::from(error));
+        assert!(matches!(
+            error.downcast_ref::<SubstreamsError>(),
+            Some(SubstreamsError::Fatal { module, .. }) if module == "map_pools"
+        ));
+        assert_eq!(
+            format!("{:#}", error),
+            "module 'map_pools' failed: panicked at 'index out of bounds'\nModule logs:\n  processing pool 0x01\n  <logs truncated>"
+        );
+    }
+
+    fn session(trace_id: &str) -> Result<Response, tonic::Status> {
+        Ok(Response {
+            message: Some(Message::Session(SessionInit {
+                trace_id: trace_id.to_string(),
+                resolved_start_block: 100,
+                linear_handoff_block: 200,
+                max_parallel_workers: 10,
+            })),
+        })
+    }
+
+    /// Streams over a fake endpoint answering each connection attempt with the next
+    /// response sequence, returns the stream's items and the number of attempts.
+    async fn stream_fake(
+        attempts: Vec<Vec<Result<Response, tonic::Status>>>,
+    ) -> (Vec<Result<BlockResponse, Error>>, usize) {
+        let attempts = Mutex::new(VecDeque::from(attempts));
+        let connected = AtomicUsize::new(0);
+
+        let stream = stream_blocks(
+            "http://fake:9000".to_string(),
+            |_request| {
+                connected.fetch_add(1, Ordering::SeqCst);
+                let responses = attempts
+                    .lock()
+                    .unwrap()
+                    .pop_front()
+                    .expect("no more connection attempts expected");
+                async move { Ok(stream::iter(responses)) }
+            },
+            Request::default(),
+            BackoffPolicy {
+                max_retries: Some(1),
+                ..Default::default()
+            },
+            Duration::from_secs(30),
+            ErrorClassifier::default(),
+            None,
+        );
+
+        let items = stream.collect().await;
+        (items, connected.into_inner())
+    }
+
+    fn error_of(items: &[Result<BlockResponse, Error>]) -> &Error {
+        items
+            .last()
+            .and_then(|item| item.as_ref().err())
+            .expect("stream ends with an error")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trace_id_of_last_session_is_attached_when_retries_are_exhausted() {
+        let (items, connected) = stream_fake(vec![
+            vec![session("abc"), Err(tonic::Status::unavailable("down"))],
+            vec![session("def"), Err(tonic::Status::unavailable("down"))],
+        ])
+        .await;
+
+        assert_eq!(connected, 2);
+        assert!(matches!(
+            &items[0],
+            Ok(BlockResponse::Session(session)) if session.linear_handoff_block == 200
+        ));
+
+        let error = error_of(&items);
+        assert!(
+            format!("{:#}", error).starts_with("trace ID def: backoff requested to stop retrying")
+        );
+        assert!(matches!(
+            error.downcast_ref::<SubstreamsError>(),
+            Some(SubstreamsError::RetriesExhausted { .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn non_retryable_and_fatal_errors_end_the_stream_with_trace_id() {
+        let (items, connected) = stream_fake(vec![vec![
+            session("abc"),
+            Err(tonic::Status::permission_denied("bad token")),
+        ]])
+        .await;
+
+        assert_eq!(connected, 1);
+        let error = error_of(&items);
+        assert!(format!("{:#}", error).starts_with("trace ID abc: non-retryable"));
+        assert!(matches!(
+            error.downcast_ref::<SubstreamsError>(),
+            Some(SubstreamsError::NonRetryable(_))
+        ));
+
+        let (items, connected) = stream_fake(vec![vec![
+            session("abc"),
+            Ok(Response {
+                message: Some(Message::FatalError(v2::Error {
+                    module: "map_pools".to_string(),
+                    reason: "panicked".to_string(),
+                    ..Default::default()
+                })),
+            }),
+        ]])
+        .await;
+
+        assert_eq!(connected, 1);
+        assert_eq!(
+            format!("{:#}", error_of(&items)),
+            "trace ID abc: module 'map_pools' failed: panicked"
+        );
+    }
+
+    #[tokio::test]
+    async fn progress_is_throttled() {
+        let progress = || {
+            Ok(Response {
+                message: Some(Message::Progress(ModulesProgress::default())),
+            })
+        };
+
+        let (items, _) = stream_fake(vec![vec![
+            session("abc"),
+            progress(),
+            progress(),
+            progress(),
+        ]])
+        .await;
+
+        let progresses = items
+            .iter()
+            .filter(|item| matches!(item, Ok(BlockResponse::Progress(_))))
+            .count();
+        assert_eq!(items.len(), 2);
+        assert_eq!(progresses, 1);
+    }
+}